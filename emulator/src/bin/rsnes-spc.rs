@@ -0,0 +1,184 @@
+//! A player for SPC music files (`*.spc`), which runs only the sound coprocessor
+
+use clap::{ErrorKind, Parser};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Sample,
+};
+use rsnes::{
+    resample::{mix_channels, Interpolation, Resampler},
+    spc700::{Spc700, StereoSample},
+    spc_file::SpcFile,
+    wav::{WavWriter, SAMPLE_RATE},
+};
+use std::{path::PathBuf, time::Duration};
+
+/// Play length of files without a length in their tags
+const DEFAULT_PLAY_SECONDS: u32 = 180;
+const DEFAULT_FADE_MILLIS: u32 = 10_000;
+
+#[derive(Parser, Clone)]
+#[clap(
+    version = clap::crate_version!(),
+)]
+struct Options {
+    /// SPC music file to play
+    #[clap(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Write the samples to a WAV file instead of playing them
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Play length in seconds (excluding the fade out),
+    /// defaults to the length given in the ID666 tags
+    #[clap(short, long)]
+    length: Option<u32>,
+
+    /// Length of the fade out in milliseconds
+    #[clap(short, long)]
+    fade: Option<u32>,
+
+    /// Print extra information that may spam your stdout
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        clap::command!().error(ErrorKind::Io, format_args!($($arg)*)).exit()
+    };
+}
+
+/// Generates the samples of the song including the fade out
+struct Player {
+    spc: Spc700,
+    sample: u64,
+    play_samples: u64,
+    fade_samples: u64,
+}
+
+impl Iterator for Player {
+    type Item = StereoSample;
+
+    fn next(&mut self) -> Option<StereoSample> {
+        if self.sample >= self.play_samples.saturating_add(self.fade_samples) {
+            return None;
+        }
+        let sample = loop {
+            if let Some(sample) = self.spc.run_cycle() {
+                break sample;
+            }
+        };
+        let faded = self.sample.saturating_sub(self.play_samples);
+        self.sample += 1;
+        Some(if faded > 0 {
+            // the fade lasts at most `u32::MAX` milliseconds, so this does not overflow
+            let volume = (self.fade_samples - faded) as i64;
+            let fade_samples = self.fade_samples as i64;
+            sample.map(|v| (i64::from(v) * volume / fade_samples) as i16)
+        } else {
+            sample
+        })
+    }
+}
+
+fn write_wav(path: &std::path::Path, player: Player) -> std::io::Result<()> {
+    let mut writer = WavWriter::create(path)?;
+    writer.write_samples(&player.collect::<Vec<_>>())?;
+    writer.finish().map(drop)
+}
+
+fn write_data<T: Sample>(data: &mut [T], consumer: &mut ringbuf::Consumer<f32>) {
+    for value in data {
+        *value = T::from(&consumer.pop().unwrap_or(0.0));
+    }
+}
+
+fn play(player: Player) -> Option<()> {
+    let device = cpal::default_host().default_output_device()?;
+    let cfg = device.default_output_config().ok()?;
+    let sample_format = cfg.sample_format();
+    let cfg = cfg.config();
+    let mut resampler = Resampler::new(Interpolation::Sinc, SAMPLE_RATE, cfg.sample_rate.0);
+    let mut frame = vec![0.0; cfg.channels.into()];
+    let buffer_size = cfg.sample_rate.0 as usize / 4 * frame.len();
+    let (mut producer, mut consumer) = ringbuf::RingBuffer::new(buffer_size).split();
+    let stream = match sample_format {
+        cpal::SampleFormat::I16 => device.build_output_stream(
+            &cfg,
+            move |data: &mut [i16], _| write_data(data, &mut consumer),
+            |_| (),
+        ),
+        cpal::SampleFormat::U16 => device.build_output_stream(
+            &cfg,
+            move |data: &mut [u16], _| write_data(data, &mut consumer),
+            |_| (),
+        ),
+        cpal::SampleFormat::F32 => device.build_output_stream(
+            &cfg,
+            move |data: &mut [f32], _| write_data(data, &mut consumer),
+            |_| (),
+        ),
+    }
+    .ok()?;
+    stream.play().ok()?;
+    for sample in player {
+        resampler.push(sample, |stereo| {
+            mix_channels(stereo, &mut frame);
+            while producer.remaining() < frame.len() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            producer.push_slice(&frame);
+        });
+    }
+    // let the remaining samples play out
+    while !producer.is_empty() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    Some(())
+}
+
+fn main() {
+    let options = Options::parse();
+    let path = &options.input;
+    let content = std::fs::read(path)
+        .unwrap_or_else(|err| error!("Could not read file \"{}\" ({})\n", path.display(), err));
+    let file = SpcFile::from_bytes(&content).unwrap_or_else(|err| {
+        error!(
+            "Failure while reading SPC file \"{}\" ({})\n",
+            path.display(),
+            err
+        )
+    });
+    let tags = file.tags.unwrap_or_default();
+    if options.verbose {
+        println!("[info] ID666 tags: {:#?}", tags);
+    } else if !tags.song_title.is_empty() {
+        println!("{} - {}", tags.game_title, tags.song_title);
+    }
+    let play_seconds = options
+        .length
+        .or(tags.play_seconds)
+        .unwrap_or(DEFAULT_PLAY_SECONDS);
+    let fade_millis = options
+        .fade
+        .or(tags.fade_millis)
+        .unwrap_or(DEFAULT_FADE_MILLIS);
+    let player = Player {
+        spc: file.spc,
+        sample: 0,
+        play_samples: u64::from(play_seconds) * u64::from(SAMPLE_RATE),
+        fade_samples: u64::from(fade_millis) * u64::from(SAMPLE_RATE / 1000),
+    };
+    match &options.output {
+        Some(output) => write_wav(output, player).unwrap_or_else(|err| {
+            error!(
+                "Could not write WAV file \"{}\" ({})\n",
+                output.display(),
+                err
+            )
+        }),
+        None => play(player).unwrap_or_else(|| error!("Failed playing on an audio output device")),
+    }
+}
@@ -29,6 +29,10 @@ pub enum ConfigLoadError {
         name: String,
         ty: &'static str,
     },
+    DuplicateScancode {
+        location: &'static str,
+        scancode: u32,
+    },
 }
 
 impl From<std::io::Error> for ConfigLoadError {
@@ -55,6 +59,9 @@ impl std::fmt::Display for ConfigLoadError {
                 write!(fmt, "unknown value \"{value}\" for field `{field}`")
             }
             Self::UndefinedName { name, ty } => write!(fmt, "undefined {ty} `{name}`"),
+            Self::DuplicateScancode { location, scancode } => {
+                write!(fmt, "scancode {scancode:#x} is bound twice in `{location}`")
+            }
         }
     }
 }
@@ -183,6 +190,19 @@ impl ControllerProfile {
         }
     }
 
+    /// Get all scancodes, this profile handles
+    fn scancodes(&self) -> Vec<u32> {
+        match self {
+            Self::Standard { scancodes: s } => [
+                s.a, s.b, s.x, s.y, s.up, s.left, s.down, s.right, s.l, s.r, s.start, s.select,
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            Self::Mouse { .. } => vec![],
+        }
+    }
+
     pub fn handle_scancode(
         &self,
         scancode: u32,
@@ -308,12 +328,333 @@ pub fn controller_profile_to_port(
     })
 }
 
+/// Number of save state slots, that can be selected by hotkeys
+pub const SAVE_STATE_SLOTS: usize = 10;
+
+/// An emulator action, that can be bound to a hotkey
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    SaveState,
+    LoadState,
+    SelectSlot(usize),
+    Reset,
+    PowerCycle,
+    Pause,
+    FrameAdvance,
+    FastForward,
+    SlowMotion,
+    Screenshot,
+    ExportSpc,
+    RecordAudio,
+    Fullscreen,
+    Quit,
+    ToggleBg(usize),
+    ToggleObj(usize),
+    ToggleMainScreen,
+    ToggleSubScreen,
+    ToggleColorMath,
+    ToggleWindows,
+    ToggleVoice(usize),
+    SoloVoice(usize),
+    PrintVoices,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hotkeys {
+    pub save_state: Option<u32>,
+    pub load_state: Option<u32>,
+    pub select_slot: [Option<u32>; SAVE_STATE_SLOTS],
+    pub reset: Option<u32>,
+    pub power_cycle: Option<u32>,
+    pub pause: Option<u32>,
+    pub frame_advance: Option<u32>,
+    pub fast_forward: Option<u32>,
+    pub slow_motion: Option<u32>,
+    pub screenshot: Option<u32>,
+    pub export_spc: Option<u32>,
+    pub record_audio: Option<u32>,
+    pub fullscreen: Option<u32>,
+    pub quit: Option<u32>,
+    pub toggle_bg: [Option<u32>; 4],
+    pub toggle_obj: [Option<u32>; 4],
+    pub toggle_main_screen: Option<u32>,
+    pub toggle_sub_screen: Option<u32>,
+    pub toggle_color_math: Option<u32>,
+    pub toggle_windows: Option<u32>,
+    pub toggle_voice: [Option<u32>; 8],
+    pub solo_voice: [Option<u32>; 8],
+    pub print_voices: Option<u32>,
+    /// Scancodes, which are bound explicitly in the config file
+    explicit: Vec<u32>,
+}
+
+/// Names of all hotkeys in the config file
+const HOTKEY_NAMES: [&str; 23] = [
+    "save-state",
+    "load-state",
+    "select-slot",
+    "reset",
+    "power-cycle",
+    "pause",
+    "frame-advance",
+    "fast-forward",
+    "slow-motion",
+    "screenshot",
+    "export-spc",
+    "record-audio",
+    "fullscreen",
+    "quit",
+    "toggle-bg",
+    "toggle-obj",
+    "toggle-main-screen",
+    "toggle-sub-screen",
+    "toggle-color-math",
+    "toggle-windows",
+    "toggle-voice",
+    "solo-voice",
+    "print-voices",
+];
+
+/// Load a single scancode, which must fit into an `u32`.
+/// `false` leaves the hotkey unbound.
+fn load_scancode(field: &'static str, val: &Value) -> Result<Option<u32>, ConfigLoadError> {
+    if let Value::Boolean(false) = val {
+        return Ok(None);
+    }
+    let code = *getval!(val, Integer)?;
+    u32::try_from(code)
+        .map(Some)
+        .map_err(|_| ConfigLoadError::UnknownValue {
+            field,
+            value: code.to_string(),
+        })
+}
+
+/// Load an array of at most `scancodes.len()` scancodes, missing entries stay unbound.
+/// `false` leaves all entries unbound.
+fn load_scancodes(
+    field: &'static str,
+    val: &Value,
+    scancodes: &mut [Option<u32>],
+) -> Result<(), ConfigLoadError> {
+    scancodes.fill(None);
+    if let Value::Boolean(false) = val {
+        return Ok(());
+    }
+    let codes = getval!(val, Array)?;
+    if codes.len() > scancodes.len() {
+        return Err(ConfigLoadError::UnknownValue {
+            field,
+            value: format!("array of {} scancodes", codes.len()),
+        });
+    }
+    for (scancode, code) in scancodes.iter_mut().zip(codes) {
+        *scancode = load_scancode(field, code)?;
+    }
+    Ok(())
+}
+
+impl Hotkeys {
+    fn load(map: &Table) -> Result<Self, ConfigLoadError> {
+        let mut slf = Self::default();
+        let mut explicit = std::collections::HashSet::new();
+        for (key, val) in map.iter() {
+            let field = HOTKEY_NAMES
+                .into_iter()
+                .find(|&name| name == key.as_str())
+                .ok_or_else(|| ConfigLoadError::UnknownField(key.clone()))?;
+            let scancodes = slf.scancodes_mut(field);
+            match &mut *scancodes {
+                [scancode] => *scancode = load_scancode(field, val)?,
+                scancodes => load_scancodes(field, val, scancodes)?,
+            }
+            for &code in scancodes.iter().flatten() {
+                if !explicit.insert(code) {
+                    return Err(ConfigLoadError::DuplicateScancode {
+                        location: "hotkeys",
+                        scancode: code,
+                    });
+                }
+            }
+        }
+        // default bindings give way to the explicitly bound scancodes
+        for field in HOTKEY_NAMES
+            .into_iter()
+            .filter(|&name| !map.contains_key(name))
+        {
+            for scancode in slf.scancodes_mut(field) {
+                if scancode.is_some_and(|code| explicit.contains(&code)) {
+                    *scancode = None;
+                }
+            }
+        }
+        slf.explicit = explicit.into_iter().collect();
+        Ok(slf)
+    }
+
+    /// Get the scancodes of the hotkey called `name` in the config file
+    fn scancodes_mut(&mut self, name: &str) -> &mut [Option<u32>] {
+        use core::slice::from_mut;
+        match name {
+            "save-state" => from_mut(&mut self.save_state),
+            "load-state" => from_mut(&mut self.load_state),
+            "select-slot" => &mut self.select_slot,
+            "reset" => from_mut(&mut self.reset),
+            "power-cycle" => from_mut(&mut self.power_cycle),
+            "pause" => from_mut(&mut self.pause),
+            "frame-advance" => from_mut(&mut self.frame_advance),
+            "fast-forward" => from_mut(&mut self.fast_forward),
+            "slow-motion" => from_mut(&mut self.slow_motion),
+            "screenshot" => from_mut(&mut self.screenshot),
+            "export-spc" => from_mut(&mut self.export_spc),
+            "record-audio" => from_mut(&mut self.record_audio),
+            "fullscreen" => from_mut(&mut self.fullscreen),
+            "quit" => from_mut(&mut self.quit),
+            "toggle-bg" => &mut self.toggle_bg,
+            "toggle-obj" => &mut self.toggle_obj,
+            "toggle-main-screen" => from_mut(&mut self.toggle_main_screen),
+            "toggle-sub-screen" => from_mut(&mut self.toggle_sub_screen),
+            "toggle-color-math" => from_mut(&mut self.toggle_color_math),
+            "toggle-windows" => from_mut(&mut self.toggle_windows),
+            "toggle-voice" => &mut self.toggle_voice,
+            "solo-voice" => &mut self.solo_voice,
+            "print-voices" => from_mut(&mut self.print_voices),
+            _ => unreachable!("unknown hotkey `{name}`"),
+        }
+    }
+
+    fn bindings(&self) -> impl Iterator<Item = (u32, Action)> + '_ {
+        [
+            (self.save_state, Action::SaveState),
+            (self.load_state, Action::LoadState),
+            (self.reset, Action::Reset),
+            (self.power_cycle, Action::PowerCycle),
+            (self.pause, Action::Pause),
+            (self.frame_advance, Action::FrameAdvance),
+            (self.fast_forward, Action::FastForward),
+            (self.slow_motion, Action::SlowMotion),
+            (self.screenshot, Action::Screenshot),
+            (self.export_spc, Action::ExportSpc),
+            (self.record_audio, Action::RecordAudio),
+            (self.fullscreen, Action::Fullscreen),
+            (self.quit, Action::Quit),
+            (self.toggle_main_screen, Action::ToggleMainScreen),
+            (self.toggle_sub_screen, Action::ToggleSubScreen),
+            (self.toggle_color_math, Action::ToggleColorMath),
+            (self.toggle_windows, Action::ToggleWindows),
+            (self.print_voices, Action::PrintVoices),
+        ]
+        .into_iter()
+        .chain(
+            self.select_slot
+                .iter()
+                .enumerate()
+                .map(|(slot, code)| (*code, Action::SelectSlot(slot))),
+        )
+        .chain(
+            self.toggle_bg
+                .iter()
+                .enumerate()
+                .map(|(nr, code)| (*code, Action::ToggleBg(nr))),
+        )
+        .chain(
+            self.toggle_obj
+                .iter()
+                .enumerate()
+                .map(|(prio, code)| (*code, Action::ToggleObj(prio))),
+        )
+        .chain(
+            self.toggle_voice
+                .iter()
+                .enumerate()
+                .map(|(voice, code)| (*code, Action::ToggleVoice(voice))),
+        )
+        .chain(
+            self.solo_voice
+                .iter()
+                .enumerate()
+                .map(|(voice, code)| (*code, Action::SoloVoice(voice))),
+        )
+        .filter_map(|(code, action)| code.map(|code| (code, action)))
+    }
+
+    pub fn find_action(&self, scancode: u32) -> Option<Action> {
+        self.bindings()
+            .find_map(|(code, action)| (code == scancode).then_some(action))
+    }
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Self {
+            save_state: Some(0x3f), // `F5`
+            load_state: Some(0x43), // `F9`
+            select_slot: [
+                Some(0x0b), // `0`
+                Some(0x02), // `1`
+                Some(0x03), // `2`
+                Some(0x04), // `3`
+                Some(0x05), // `4`
+                Some(0x06), // `5`
+                Some(0x07), // `6`
+                Some(0x08), // `7`
+                Some(0x09), // `8`
+                Some(0x0a), // `9`
+            ],
+            reset: Some(0x3d),         // `F3`
+            power_cycle: Some(0x3e),   // `F4`
+            pause: Some(0x19),         // QWERTY `P`
+            frame_advance: Some(0x18), // QWERTY `O`
+            fast_forward: Some(0x0f),  // QWERTY `Tab`
+            slow_motion: Some(0x29),   // QWERTY `^`/`~`
+            screenshot: Some(0x58),    // `F12`
+            export_spc: Some(0x42),    // `F8`
+            record_audio: Some(0x41),  // `F7`
+            fullscreen: Some(0x57),    // `F11`
+            quit: Some(0x01),          // `Escape`
+            toggle_bg: [
+                Some(0x4f), // `Keypad 1`
+                Some(0x50), // `Keypad 2`
+                Some(0x51), // `Keypad 3`
+                Some(0x4b), // `Keypad 4`
+            ],
+            toggle_obj: [
+                Some(0x4c), // `Keypad 5`
+                Some(0x4d), // `Keypad 6`
+                Some(0x47), // `Keypad 7`
+                Some(0x48), // `Keypad 8`
+            ],
+            toggle_main_screen: Some(0x49), // `Keypad 9`
+            toggle_sub_screen: Some(0x52),  // `Keypad 0`
+            toggle_color_math: Some(0x53),  // `Keypad .`
+            toggle_windows: Some(0x4e),     // `Keypad +`
+            toggle_voice: [None; 8],
+            solo_voice: [None; 8],
+            print_voices: Some(0x44), // `F10`
+            explicit: Vec::new(),
+        }
+    }
+}
+
+/// The audio latency, if it is not configured in the profile
+const DEFAULT_AUDIO_LATENCY: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[derive(Debug, Clone)]
 pub struct Profile {
     pub port1: Option<String>,
     pub port2: Option<String>,
     pub region: rsnes::cartridge::CountryFrameRate,
     pub threaded: bool,
+    pub render_mode: rsnes::ppu::RenderMode,
+    pub unlimited_sprites: bool,
+    pub mode7_scale: u8,
+    pub mode7_perspective: bool,
+    pub color_profile: rsnes::color::ColorProfile,
+    pub gamma: f32,
+    pub resampler: rsnes::resample::Interpolation,
+    pub audio_latency: std::time::Duration,
+    pub fast_forward_speed: f64,
+    pub slow_motion_speed: f64,
 }
 
 impl Profile {
@@ -345,11 +686,138 @@ impl Profile {
             .transpose()?
             .copied()
             .unwrap_or(true);
+        let render_mode = map
+            .get("renderer")
+            .map(|v| getval!(v, String))
+            .transpose()?
+            .and_then(|renderer| match renderer.as_str() {
+                "scanline" => Some(rsnes::ppu::RenderMode::Scanline),
+                "dot" => Some(rsnes::ppu::RenderMode::Dot),
+                _ => None,
+            })
+            .unwrap_or(rsnes::ppu::RenderMode::Scanline);
+        let unlimited_sprites = map
+            .get("unlimited-sprites")
+            .map(|v| getval!(v, Boolean))
+            .transpose()?
+            .copied()
+            .unwrap_or(false);
+        let mode7_scale = map
+            .get("mode7-scale")
+            .map(|v| getval!(v, Integer))
+            .transpose()?
+            .map(|&scale| {
+                u8::try_from(scale)
+                    .ok()
+                    .filter(|scale| (1..=rsnes::ppu::MAX_MODE7_SCALE).contains(scale))
+                    .ok_or_else(|| ConfigLoadError::UnknownValue {
+                        field: "mode7-scale",
+                        value: scale.to_string(),
+                    })
+            })
+            .transpose()?
+            .unwrap_or(1);
+        let mode7_perspective = map
+            .get("mode7-perspective")
+            .map(|v| getval!(v, Boolean))
+            .transpose()?
+            .copied()
+            .unwrap_or(true);
+        let color_profile = map
+            .get("color-profile")
+            .map(|v| getval!(v, String))
+            .transpose()?
+            .map(|profile| match profile.as_str() {
+                "direct" => Ok(rsnes::color::ColorProfile::Direct),
+                "crt" => Ok(rsnes::color::ColorProfile::Crt),
+                "lcd" => Ok(rsnes::color::ColorProfile::Lcd),
+                _ => Err(ConfigLoadError::UnknownValue {
+                    field: "color-profile",
+                    value: profile.clone(),
+                }),
+            })
+            .transpose()?
+            .unwrap_or(rsnes::color::ColorProfile::Direct);
+        let gamma = map
+            .get("gamma")
+            .map(|v| getval!(v, Float))
+            .transpose()?
+            .map(|&gamma| {
+                Some(gamma as f32)
+                    .filter(|gamma| gamma.is_finite() && *gamma > 0.0)
+                    .ok_or_else(|| ConfigLoadError::UnknownValue {
+                        field: "gamma",
+                        value: gamma.to_string(),
+                    })
+            })
+            .transpose()?
+            .unwrap_or(1.0);
+        let resampler = map
+            .get("resampler")
+            .map(|v| getval!(v, String))
+            .transpose()?
+            .map(|resampler| match resampler.as_str() {
+                "linear" => Ok(rsnes::resample::Interpolation::Linear),
+                "cubic" => Ok(rsnes::resample::Interpolation::Cubic),
+                "sinc" => Ok(rsnes::resample::Interpolation::Sinc),
+                _ => Err(ConfigLoadError::UnknownValue {
+                    field: "resampler",
+                    value: resampler.clone(),
+                }),
+            })
+            .transpose()?
+            .unwrap_or(rsnes::resample::Interpolation::Sinc);
+        let audio_latency = map
+            .get("audio-latency")
+            .map(|v| getval!(v, Integer))
+            .transpose()?
+            .map(|&millis| {
+                u64::try_from(millis)
+                    .ok()
+                    .filter(|millis| (1..=1000).contains(millis))
+                    .map(std::time::Duration::from_millis)
+                    .ok_or_else(|| ConfigLoadError::UnknownValue {
+                        field: "audio-latency",
+                        value: millis.to_string(),
+                    })
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_AUDIO_LATENCY);
+        macro_rules! get_speed {
+            ($name:literal, $default:expr, $is_valid:expr) => {
+                map.get($name)
+                    .map(|v| getval!(v, Float))
+                    .transpose()?
+                    .map(|&speed| {
+                        Some(speed)
+                            .filter(|&speed| speed.is_finite() && $is_valid(speed))
+                            .ok_or_else(|| ConfigLoadError::UnknownValue {
+                                field: $name,
+                                value: speed.to_string(),
+                            })
+                    })
+                    .transpose()?
+                    .unwrap_or($default)
+            };
+        }
+        // a fast-forward speed of 0 means unthrottled
+        let fast_forward_speed = get_speed!("fast-forward-speed", 0.0, |speed| speed >= 0.0);
+        let slow_motion_speed = get_speed!("slow-motion-speed", 0.5, |speed| speed > 0.0);
         Ok(Self {
             port1,
             port2,
             region,
             threaded,
+            render_mode,
+            unlimited_sprites,
+            mode7_scale,
+            mode7_perspective,
+            color_profile,
+            gamma,
+            resampler,
+            audio_latency,
+            fast_forward_speed,
+            slow_motion_speed,
         })
     }
 }
@@ -361,6 +829,16 @@ impl Default for Profile {
             port2: None,
             region: rsnes::cartridge::CountryFrameRate::Any,
             threaded: true,
+            render_mode: rsnes::ppu::RenderMode::Scanline,
+            unlimited_sprites: false,
+            mode7_scale: 1,
+            mode7_perspective: true,
+            color_profile: rsnes::color::ColorProfile::Direct,
+            gamma: 1.0,
+            resampler: rsnes::resample::Interpolation::Sinc,
+            audio_latency: DEFAULT_AUDIO_LATENCY,
+            fast_forward_speed: 0.0,
+            slow_motion_speed: 0.5,
         }
     }
 }
@@ -370,6 +848,7 @@ pub struct Config {
     default_profile: String,
     profiles: HashMap<String, Profile>,
     controller_profiles: HashMap<String, ControllerProfile>,
+    pub hotkeys: Hotkeys,
 }
 
 impl Default for Config {
@@ -378,6 +857,7 @@ impl Default for Config {
             default_profile: String::from("default"),
             profiles: [(String::from("default"), Profile::default())].into(),
             controller_profiles: [(String::from("default"), ControllerProfile::default())].into(),
+            hotkeys: Hotkeys::default(),
         }
     }
 }
@@ -422,6 +902,7 @@ impl Config {
         let mut controller_profiles = Default::default();
         let mut profiles = Default::default();
         let mut default_profile = None;
+        let mut hotkeys = Hotkeys::default();
         for (key, val) in main.iter() {
             match key.as_str() {
                 "default-profile" => {
@@ -431,6 +912,7 @@ impl Config {
                 "controller-profiles" => {
                     controller_profiles = Self::load_controller_profiles(getval!(val, Table)?)?
                 }
+                "hotkeys" => hotkeys = Hotkeys::load(getval!(val, Table)?)?,
                 _ => return Err(ConfigLoadError::UnknownField(key.clone())),
             }
         }
@@ -442,8 +924,10 @@ impl Config {
             default_profile,
             profiles,
             controller_profiles,
+            hotkeys,
         };
         slf.validate_names()?;
+        slf.warn_shadowed_hotkeys();
         Ok(slf)
     }
 
@@ -467,6 +951,20 @@ impl Config {
         Ok(())
     }
 
+    /// Controller profiles handle scancodes before the hotkeys,
+    /// so warn about explicitly bound hotkeys, which will never trigger
+    fn warn_shadowed_hotkeys(&self) {
+        for (name, profile) in &self.controller_profiles {
+            for code in profile.scancodes() {
+                if self.hotkeys.explicit.contains(&code) {
+                    eprintln!(
+                        "[warning] hotkey scancode {code:#x} is shadowed by controller profile `{name}`"
+                    );
+                }
+            }
+        }
+    }
+
     pub fn seek_config_path() -> Option<PathBuf> {
         CONFIG_FILE_PATHS
             .iter()
@@ -1,21 +1,34 @@
 mod config;
 
 use clap::{ErrorKind, Parser};
+use config::Action;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
     Sample,
 };
 use pollster::FutureExt;
-use rsnes::{backend::ArrayFrameBuffer, device::Device, spc700::StereoSample};
+use rsnes::{
+    backend::{ArrayFrameBuffer, PixelFormat},
+    color::{ColorPipeline, ColorProfile},
+    device::Device,
+    resample::{mix_channels, Interpolation, RateControl, Resampler},
+    spc700::StereoSample,
+    spc_file::{Id666, SpcFile},
+    wav::{Recorder, RecordingHandle},
+};
 use save_state::InSaveState;
 use std::{
     path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use winit::{
     event::{DeviceEvent, ElementState, Event, KeyboardInput, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Fullscreen, WindowBuilder},
 };
 
 const MASTER_CYCLES_PER_TICK: u16 = 2;
@@ -40,6 +53,15 @@ struct Options {
     /// Use a specified profile of your configuration
     #[clap(short, long)]
     profile: Option<String>,
+
+    /// Record the audio output to a WAV file from the start
+    #[clap(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// Record every S-DSP voice to a separate WAV file in addition
+    /// (applies to the recording hotkey as well)
+    #[clap(long)]
+    record_voices: bool,
 }
 
 macro_rules! error {
@@ -48,20 +70,231 @@ macro_rules! error {
     };
 }
 
-fn cartridge_from_file(path: &std::path::Path) -> rsnes::cartridge::Cartridge {
+fn cartridge_from_file(path: &std::path::Path, verbose: bool) -> rsnes::cartridge::Cartridge {
     let content = std::fs::read(path)
         .unwrap_or_else(|err| error!("Could not read file \"{}\" ({})\n", path.display(), err));
-    rsnes::cartridge::Cartridge::from_bytes(&content).unwrap_or_else(|err| {
+    let mut cartridge = rsnes::cartridge::Cartridge::from_bytes(&content).unwrap_or_else(|err| {
         error!(
             "Failure while reading cartridge file \"{}\" ({})\n",
             path.display(),
             err
         )
-    })
+    });
+    match rsnes::enhancement::msu1::Msu1::open(path) {
+        Ok(Some(msu1)) => {
+            if verbose {
+                println!("[info] Found MSU-1 data file");
+            }
+            cartridge.attach_msu1(msu1)
+        }
+        Ok(None) => (),
+        Err(err) => error!("Could not read MSU-1 data file ({})\n", err),
+    }
+    cartridge
+}
+
+/// Get the RGBA pixels of the current frame.
+///
+/// Raw console colors get converted by the `color_pipeline` into `converted`.
+fn frame_pixels<'a>(
+    frame_buffer: &'a ArrayFrameBuffer,
+    color_pipeline: Option<&ColorPipeline>,
+    converted: &'a mut Vec<[u8; 4]>,
+) -> &'a [[u8; 4]] {
+    let pixels = &frame_buffer.0[..(frame_buffer.width() * frame_buffer.height()) as usize];
+    match color_pipeline {
+        Some(color_pipeline) => {
+            converted.resize(pixels.len(), [0; 4]);
+            color_pipeline.convert_frame(pixels, converted);
+            converted
+        }
+        None => pixels,
+    }
+}
+
+/// Save the visible frame as a PPM image next to the cartridge file
+fn save_screenshot(
+    pixels: &[[u8; 4]],
+    (width, height): (u32, u32),
+    rom_path: &std::path::Path,
+) -> std::io::Result<()> {
+    let path = (0..)
+        .map(|i| {
+            let mut name = rom_path.with_extension("").into_os_string();
+            name.push(format!("-screenshot-{i}.ppm"));
+            PathBuf::from(name)
+        })
+        .find(|path| !path.exists())
+        .unwrap();
+    let mut data = format!("P6\n{width} {height}\n255\n").into_bytes();
+    for pixel in pixels {
+        data.extend_from_slice(&pixel[..3])
+    }
+    std::fs::write(&path, data)?;
+    println!("[info] saved screenshot to `{}`", path.display());
+    Ok(())
+}
+
+fn save_spc(
+    spc: rsnes::spc700::Spc700,
+    game_title: &str,
+    rom_path: &std::path::Path,
+) -> std::io::Result<()> {
+    let path = (0..)
+        .map(|i| {
+            let mut name = rom_path.with_extension("").into_os_string();
+            name.push(format!("-{i}.spc"));
+            PathBuf::from(name)
+        })
+        .find(|path| !path.exists())
+        .unwrap();
+    let tags = Id666 {
+        game_title: game_title.to_owned(),
+        dumper: env!("CARGO_PKG_NAME").to_owned(),
+        ..Id666::default()
+    };
+    let file = SpcFile {
+        spc,
+        tags: Some(tags),
+    };
+    std::fs::write(&path, file.to_bytes())?;
+    println!("[info] saved SPC file to `{}`", path.display());
+    Ok(())
+}
+
+/// Start recording the audio output to `path`
+fn start_recording(
+    recording: &RecordingHandle,
+    path: &std::path::Path,
+    voices: bool,
+) -> std::io::Result<()> {
+    recording.start(path, voices)?;
+    println!("[info] recording audio to `{}`", path.display());
+    Ok(())
+}
+
+/// Stop the recording and report failures
+fn stop_recording(recording: &RecordingHandle) {
+    if recording.is_recording() {
+        let seconds = f64::from(recording.len()) / f64::from(rsnes::wav::SAMPLE_RATE);
+        match recording.stop() {
+            Ok(()) => println!("[info] stopped recording after {seconds:.1}s"),
+            Err(err) => eprintln!("[warning] could not write recording ({err})"),
+        }
+    }
+}
+
+/// Toggle the rendering feature selected by a layer toggle `action`.
+///
+/// Returns the name of the feature and if it is drawn now.
+fn toggle_layer(mask: &mut rsnes::ppu::LayerMask, action: Action) -> (String, bool) {
+    let (name, enabled) = match action {
+        Action::ToggleBg(nr) => (format!("BG{}", nr + 1), &mut mask.bgs[nr]),
+        Action::ToggleObj(prio) => (format!("OBJ priority {prio}"), &mut mask.objs[prio]),
+        Action::ToggleMainScreen => ("main screen".into(), &mut mask.main_screen),
+        Action::ToggleSubScreen => ("sub screen".into(), &mut mask.sub_screen),
+        Action::ToggleColorMath => ("color math".into(), &mut mask.color_math),
+        Action::ToggleWindows => ("windows".into(), &mut mask.windows),
+        _ => unreachable!(),
+    };
+    *enabled ^= true;
+    (name, *enabled)
+}
+
+/// Print the state of all S-DSP voices
+fn print_voices(spc: &rsnes::spc700::Spc700, mask: rsnes::spc700::VoiceMask) {
+    println!("voice  pitch  srcn  period   envelope  brr     key-on  audible");
+    for voice in 0..8 {
+        let state = spc.dsp().voice_state(voice);
+        println!(
+            "{voice:<5}  {:#06x} {:#04x}  {:<8} {:#05x}     {:#06x}  {:<6}  {}",
+            state.pitch,
+            state.srcn,
+            format!("{:?}", state.period),
+            state.envelope,
+            state.brr_addr,
+            state.is_keyed_on,
+            mask.is_audible(voice),
+        );
+    }
+}
+
+/// The texture, that the frame buffer gets copied to.
+/// It is recreated, when the size of the frame buffer changes.
+struct ScreenTexture {
+    texture: wgpu::Texture,
+    extent: wgpu::Extent3d,
+    bind_group: wgpu::BindGroup,
+}
+
+impl ScreenTexture {
+    fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        screen_size_buffer: &wgpu::Buffer,
+        frame_buffer: &ArrayFrameBuffer,
+    ) -> Self {
+        let extent = wgpu::Extent3d {
+            width: frame_buffer.width(),
+            height: frame_buffer.height(),
+            depth_or_array_layers: 1,
+        };
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: None,
+            format: Some(format),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: 0,
+            array_layer_count: None,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: screen_size_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        Self {
+            texture,
+            extent,
+            bind_group,
+        }
+    }
 }
 
 struct AudioBackend {
-    producer: ringbuf::Producer<i16>,
+    producer: ringbuf::Producer<f32>,
+    resampler: Resampler,
+    /// adjusts the resampler to keep the ringbuf filled to the latency target
+    rate_control: RateControl,
+    /// a single frame with a sample for every channel of the device
+    frame: Vec<f32>,
+    /// drop all samples instead of overrunning the ringbuf (e.g. while fast-forwarding)
+    muted: Arc<AtomicBool>,
 }
 
 const SAMPLE_RATE: cpal::SampleRate = cpal::SampleRate(32000);
@@ -69,17 +302,15 @@ const TIME_PER_GPU_FRAME: Duration = Duration::from_micros(8_333);
 const TIME_UNTIL_TIMER_RESET: Duration = Duration::from_millis(500);
 
 impl AudioBackend {
-    fn write_data<T: Sample>(data: &mut [T], consumer: &mut ringbuf::Consumer<i16>, channels: u16) {
+    fn write_data<T: Sample>(data: &mut [T], consumer: &mut ringbuf::Consumer<f32>, channels: u16) {
         for frame in data.chunks_exact_mut(channels.into()) {
-            let [l, r] = [(), ()].map(|_| T::from(&consumer.pop().unwrap_or(0)));
-            if channels == 2 {
-                frame[0] = l;
-                frame[1] = r;
-            } else {
-                // TODO: join channels together
-                for i in 0..channels {
-                    frame[usize::from(i)] = l;
-                }
+            // only take whole frames to keep the channels in order
+            if consumer.len() < frame.len() {
+                frame.fill(T::from(&0.0f32));
+                continue;
+            }
+            for value in frame {
+                *value = T::from(&consumer.pop().unwrap_or(0.0));
             }
         }
     }
@@ -87,24 +318,16 @@ impl AudioBackend {
     fn create_stream<T: Sample>(
         device: &cpal::Device,
         cfg: &cpal::StreamConfig,
+        ringbuf_size: usize,
     ) -> Result<
         (
             <cpal::Device as DeviceTrait>::Stream,
-            ringbuf::Producer<i16>,
+            ringbuf::Producer<f32>,
         ),
         cpal::BuildStreamError,
     > {
         let channels = cfg.channels;
-        let ringbuf_size = (match cfg.buffer_size {
-            cpal::BufferSize::Fixed(val) => val,
-            cpal::BufferSize::Default => 1024,
-        } + cfg.sample_rate.0 / 6)
-            * u32::from(channels);
-        let (mut producer, mut consumer) = ringbuf::RingBuffer::new(ringbuf_size as usize).split();
-        // add a little latency
-        for _ in 0..ringbuf_size / 5 {
-            producer.push(0).unwrap();
-        }
+        let (producer, mut consumer) = ringbuf::RingBuffer::new(ringbuf_size).split();
         device
             .build_output_stream(
                 cfg,
@@ -114,58 +337,152 @@ impl AudioBackend {
             .map(|stream| (stream, producer))
     }
 
-    fn new() -> Option<(Self, cpal::platform::Stream)> {
+    fn new(
+        muted: Arc<AtomicBool>,
+        interpolation: Interpolation,
+        latency: Duration,
+    ) -> Option<(Self, cpal::platform::Stream)> {
         let host = cpal::available_hosts()
             .into_iter()
             .find_map(|id| cpal::host_from_id(id).ok())
             .unwrap_or_else(cpal::default_host);
         let device = host.default_output_device()?;
-        let cfg_range = device
-            .supported_output_configs()
-            .ok()?
-            // TODO: implement resampling
-            .filter(|cfg| (cfg.min_sample_rate()..=cfg.max_sample_rate()).contains(&SAMPLE_RATE))
-            .min_by_key(|cfg| {
-                (
-                    match cfg.channels() {
-                        0 => u16::MAX,
-                        1 => 12,
-                        2 => 0,
-                        n => n,
-                    },
-                    match cfg.sample_format() {
-                        cpal::SampleFormat::I16 => 0u8,
-                        cpal::SampleFormat::U16 => 1,
-                        cpal::SampleFormat::F32 => 2,
-                    },
-                    match cfg.buffer_size() {
-                        cpal::SupportedBufferSize::Unknown => cpal::FrameCount::MAX,
-                        cpal::SupportedBufferSize::Range { min, .. } => *min,
-                    },
-                )
-            })?;
+        let default_sample_rate = device
+            .default_output_config()
+            .map(|cfg| cfg.sample_rate())
+            .unwrap_or(cpal::SampleRate(48000));
+        let cfg_range = device.supported_output_configs().ok()?.min_by_key(|cfg| {
+            (
+                match cfg.channels() {
+                    0 => u16::MAX,
+                    1 => 12,
+                    2 => 0,
+                    n => n,
+                },
+                // no resampling is needed at the native rate
+                !(cfg.min_sample_rate()..=cfg.max_sample_rate()).contains(&SAMPLE_RATE),
+                match cfg.sample_format() {
+                    cpal::SampleFormat::I16 => 0u8,
+                    cpal::SampleFormat::U16 => 1,
+                    cpal::SampleFormat::F32 => 2,
+                },
+                match cfg.buffer_size() {
+                    cpal::SupportedBufferSize::Unknown => cpal::FrameCount::MAX,
+                    cpal::SupportedBufferSize::Range { min, .. } => *min,
+                },
+            )
+        })?;
         let sample_type = cfg_range.sample_format();
-        let sample_rate =
-            SAMPLE_RATE.clamp(cfg_range.min_sample_rate(), cfg_range.max_sample_rate());
+        let (min_rate, max_rate) = (cfg_range.min_sample_rate(), cfg_range.max_sample_rate());
+        let sample_rate = if (min_rate..=max_rate).contains(&SAMPLE_RATE) {
+            SAMPLE_RATE
+        } else {
+            default_sample_rate.clamp(min_rate, max_rate)
+        };
         let cfg = cfg_range.with_sample_rate(sample_rate).config();
+        let channels = usize::from(cfg.channels);
+        let target_fill =
+            (latency.as_secs_f64() * f64::from(sample_rate.0)).ceil() as usize * channels;
+        let device_buffer = match cfg.buffer_size {
+            cpal::BufferSize::Fixed(val) => val as usize,
+            cpal::BufferSize::Default => 1024,
+        } * channels;
+        // leave enough room for the rate control to work above the target
+        let ringbuf_size = 2 * target_fill + device_buffer;
 
         let create_stream = match sample_type {
             cpal::SampleFormat::I16 => Self::create_stream::<i16>,
             cpal::SampleFormat::U16 => Self::create_stream::<u16>,
             cpal::SampleFormat::F32 => Self::create_stream::<f32>,
         };
-        let (stream, producer) = create_stream(&device, &cfg).ok()?;
+        let (stream, producer) = create_stream(&device, &cfg, ringbuf_size).ok()?;
         stream.play().ok()?;
-        Some((Self { producer }, stream))
+        let resampler = Resampler::new(interpolation, SAMPLE_RATE.0, sample_rate.0);
+        let backend = Self {
+            producer,
+            rate_control: RateControl::new(resampler.step(), target_fill),
+            resampler,
+            frame: vec![0.0; channels],
+            muted,
+        };
+        Some((backend, stream))
     }
 }
 
 impl rsnes::backend::AudioBackend for AudioBackend {
     fn push_sample(&mut self, sample: StereoSample) {
-        let _ = self
-            .producer
-            .push(sample.l)
-            .and_then(|()| self.producer.push(sample.r));
+        self.push_samples(core::slice::from_ref(&sample))
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample]) {
+        if self.muted.load(Ordering::Relaxed) {
+            return;
+        }
+        let Self {
+            producer,
+            resampler,
+            rate_control,
+            frame,
+            ..
+        } = self;
+        if producer.is_empty() {
+            // start with the target latency again after an underrun
+            // (e.g. at the beginning or after fast-forwarding)
+            for _ in 0..rate_control.target_fill() {
+                if producer.push(0.0).is_err() {
+                    break;
+                }
+            }
+        }
+        resampler.set_step(rate_control.step(producer.len()));
+        for sample in samples {
+            resampler.push(*sample, |stereo| {
+                mix_channels(stereo, frame);
+                if producer.remaining() >= frame.len() {
+                    producer.push_slice(frame);
+                }
+            });
+        }
+    }
+}
+
+/// The emulation speed state, which is controlled by hotkeys
+#[derive(Debug, Default)]
+struct EmulationSpeed {
+    paused: bool,
+    frame_advance: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+}
+
+impl EmulationSpeed {
+    fn handle_action(&mut self, action: config::Action, is_pressed: bool) {
+        use config::Action::*;
+        match action {
+            Pause if is_pressed => self.paused ^= true,
+            FrameAdvance if is_pressed => {
+                // the first press only pauses the emulation
+                self.frame_advance = self.paused;
+                self.paused = true;
+            }
+            FastForward => self.fast_forward = is_pressed,
+            SlowMotion if is_pressed => self.slow_motion ^= true,
+            _ => (),
+        }
+    }
+
+    /// Get the speed multiplier or `None` for an unthrottled emulation
+    fn factor(&self, fast_forward_speed: f64, slow_motion_speed: f64) -> Option<f64> {
+        let factor = if self.paused {
+            1.0
+        } else if self.fast_forward {
+            fast_forward_speed
+        } else if self.slow_motion {
+            slow_motion_speed
+        } else {
+            1.0
+        };
+        (factor > 0.0).then_some(factor)
     }
 }
 
@@ -212,7 +529,7 @@ fn main() {
     let [port1_profile, port2_profile] =
         config.get_controller_profiles(&profile).map(|p| p.cloned());
 
-    let cartridge = cartridge_from_file(&options.input);
+    let cartridge = cartridge_from_file(&options.input, options.verbose);
     let title = cartridge.title().to_owned();
     if options.verbose {
         println!(
@@ -234,17 +551,47 @@ fn main() {
             if is_pal { "PAL" } else { "NTSC" }
         );
     }
-    let (audio_backend, _audio_stream) =
-        AudioBackend::new().unwrap_or_else(|| error!("Failed finding an audio output device"));
+    let audio_muted = Arc::new(AtomicBool::new(false));
+    let (audio_backend, _audio_stream) = AudioBackend::new(
+        Arc::clone(&audio_muted),
+        profile.resampler,
+        profile.audio_latency,
+    )
+    .unwrap_or_else(|| error!("Failed finding an audio output device"));
+    let audio_backend = Recorder::new(audio_backend);
+    let recording = audio_backend.handle();
+    // the PPU only outputs raw colors, if they need to be converted differently
+    let color_pipeline = (profile.color_profile != ColorProfile::Direct || profile.gamma != 1.0)
+        .then(|| ColorPipeline::new(profile.color_profile, profile.gamma));
+    let mut converted_frame = vec![];
+    let pixel_format = if color_pipeline.is_some() {
+        PixelFormat::Bgr555
+    } else {
+        PixelFormat::Rgba8
+    };
     let mut snes = Device::new(
         audio_backend,
-        ArrayFrameBuffer([[0; 4]; rsnes::backend::FRAME_BUFFER_SIZE], true),
+        ArrayFrameBuffer::new().with_pixel_format(pixel_format),
         is_pal,
         profile.threaded,
     );
+    snes.ppu.set_render_mode(profile.render_mode);
+    snes.ppu.set_unlimited_sprites(profile.unlimited_sprites);
+    snes.ppu.set_mode7_scale(profile.mode7_scale);
+    snes.ppu.set_mode7_perspective(profile.mode7_perspective);
     snes.controllers.port1 = config::controller_profile_to_port(port1_profile.as_ref());
     snes.controllers.port2 = config::controller_profile_to_port(port2_profile.as_ref());
     snes.load_cartridge(cartridge);
+    if let Some(path) = &options.record {
+        snes.smp.set_voice_recording(options.record_voices);
+        start_recording(&recording, path, options.record_voices).unwrap_or_else(|err| {
+            error!(
+                "Could not create WAV file \"{}\" ({})\n",
+                path.display(),
+                err
+            )
+        });
+    }
 
     let size = winit::dpi::PhysicalSize::new(
         rsnes::ppu::SCREEN_WIDTH * 4,
@@ -322,31 +669,6 @@ fn main() {
         bind_group_layouts: &[&bind_group_layout],
         push_constant_ranges: &[],
     });
-    let texture_extent = wgpu::Extent3d {
-        width: rsnes::ppu::SCREEN_WIDTH,
-        height: rsnes::ppu::MAX_SCREEN_HEIGHT,
-        depth_or_array_layers: 1,
-    };
-    let texture_format = wgpu::TextureFormat::Rgba8UnormSrgb;
-    let texture = device.create_texture(&wgpu::TextureDescriptor {
-        label: None,
-        size: texture_extent,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: texture_format,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-    });
-    let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-        label: None,
-        format: Some(texture_format),
-        dimension: Some(wgpu::TextureViewDimension::D2),
-        aspect: wgpu::TextureAspect::All,
-        base_mip_level: 0,
-        mip_level_count: None,
-        base_array_layer: 0,
-        array_layer_count: None,
-    });
     let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
         label: None,
         address_mode_u: wgpu::AddressMode::MirrorRepeat,
@@ -367,24 +689,13 @@ fn main() {
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         mapped_at_creation: false,
     });
-    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: None,
-        layout: &bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&texture_view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: wgpu::BindingResource::Sampler(&sampler),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: screen_size_buffer.as_entire_binding(),
-            },
-        ],
-    });
+    let mut screen_texture = ScreenTexture::new(
+        &device,
+        &bind_group_layout,
+        &sampler,
+        &screen_size_buffer,
+        &snes.ppu.frame_buffer,
+    );
 
     let swapchain_format = surf.get_preferred_format(&adapter).unwrap();
     let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -414,8 +725,15 @@ fn main() {
     };
     surf.configure(&device, &surf_config);
 
-    let mut shift = [false; 2];
-    let mut savestates: [Option<Vec<u8>>; 10] = [(); 10].map(|()| None);
+    let hotkeys = config.hotkeys.clone();
+    let (fast_forward_speed, slow_motion_speed) =
+        (profile.fast_forward_speed, profile.slow_motion_speed);
+    let mut speed = EmulationSpeed::default();
+
+    let mut selected_slot = 0;
+    let mut voice_mask = rsnes::spc700::VoiceMask::default();
+    let mut savestates: [Option<Vec<u8>>; config::SAVE_STATE_SLOTS] =
+        [(); config::SAVE_STATE_SLOTS].map(|()| None);
 
     let mut next_device_update = Instant::now();
     let mut next_graphics_update = next_device_update;
@@ -503,35 +821,125 @@ fn main() {
                             break;
                         }
                     }
-                    if !handled {
-                        match scancode {
-                            _ => {
-                                match scancode {
-                                    0x2a => shift[0] = state == winit::event::ElementState::Pressed,
-                                    0x36 => shift[1] = state == winit::event::ElementState::Pressed,
-                                    2..=11 if state == winit::event::ElementState::Pressed => {
-                                        let id = if scancode == 11 { 0 } else { scancode - 1 };
-                                        let state = &mut savestates[id as usize];
-                                        if shift[0] || shift[1] {
-                                            if let Some(state) = state {
-                                                // load save state
-                                                let mut deserializer =
-                                                    save_state::SaveStateDeserializer {
-                                                        data: state.iter(),
-                                                    };
-                                                snes.deserialize(&mut deserializer);
-                                            }
-                                        } else {
-                                            // store save state
-                                            let mut serializer =
-                                                save_state::SaveStateSerializer { data: vec![] };
-                                            snes.serialize(&mut serializer);
-                                            *state = Some(serializer.data);
-                                        }
-                                    }
-                                    _ => (),
+                    if let Some(action) = hotkeys.find_action(scancode).filter(|_| !handled) {
+                        let is_pressed = state == ElementState::Pressed;
+                        match action {
+                            Action::SaveState if is_pressed => {
+                                snes.smp.synchronize();
+                                if let Some(err) = snes.smp.error() {
+                                    eprintln!("[warning] could not save state ({err})");
+                                } else {
+                                    let mut serializer =
+                                        save_state::SaveStateSerializer { data: vec![] };
+                                    snes.serialize(&mut serializer);
+                                    savestates[selected_slot] = Some(serializer.data);
+                                }
+                            }
+                            Action::LoadState if is_pressed => {
+                                if let Some(state) = &savestates[selected_slot] {
+                                    let mut deserializer =
+                                        save_state::SaveStateDeserializer { data: state.iter() };
+                                    snes.deserialize(&mut deserializer);
+                                }
+                            }
+                            Action::SelectSlot(slot) if is_pressed => {
+                                selected_slot = slot;
+                                if options.verbose {
+                                    println!("[info] selected save state slot {slot}");
                                 }
                             }
+                            Action::Reset if is_pressed => snes.reset(),
+                            Action::PowerCycle if is_pressed => snes.power_cycle(),
+                            Action::Screenshot if is_pressed => {
+                                let frame_buffer = &snes.ppu.frame_buffer;
+                                let pixels = frame_pixels(
+                                    frame_buffer,
+                                    color_pipeline.as_ref(),
+                                    &mut converted_frame,
+                                );
+                                let size = (frame_buffer.width(), frame_buffer.height());
+                                save_screenshot(pixels, size, &options.input).unwrap_or_else(
+                                    |err| eprintln!("[warning] could not save screenshot ({err})"),
+                                );
+                            }
+                            Action::ExportSpc if is_pressed => {
+                                let result = match snes.smp.get_spc() {
+                                    Ok(spc) => save_spc(spc, &title, &options.input)
+                                        .map_err(|err| err.to_string()),
+                                    Err(err) => Err(err.to_string()),
+                                };
+                                result.unwrap_or_else(|err| {
+                                    eprintln!("[warning] could not save SPC file ({err})")
+                                });
+                            }
+                            Action::RecordAudio if is_pressed => {
+                                if recording.is_recording() {
+                                    snes.smp.flush_samples_and_wait();
+                                    stop_recording(&recording);
+                                    snes.smp.set_voice_recording(false);
+                                } else {
+                                    let path = (0..)
+                                        .map(|i| {
+                                            let mut name =
+                                                options.input.with_extension("").into_os_string();
+                                            name.push(format!("-recording-{i}.wav"));
+                                            PathBuf::from(name)
+                                        })
+                                        .find(|path| !path.exists())
+                                        .unwrap();
+                                    snes.smp.set_voice_recording(options.record_voices);
+                                    start_recording(&recording, &path, options.record_voices)
+                                        .unwrap_or_else(|err| {
+                                            eprintln!("[warning] could not start recording ({err})")
+                                        });
+                                }
+                            }
+                            Action::Fullscreen if is_pressed => {
+                                window.set_fullscreen(match window.fullscreen() {
+                                    Some(_) => None,
+                                    None => Some(Fullscreen::Borderless(None)),
+                                })
+                            }
+                            Action::Quit => *control_flow = ControlFlow::Exit,
+                            Action::ToggleBg(_)
+                            | Action::ToggleObj(_)
+                            | Action::ToggleMainScreen
+                            | Action::ToggleSubScreen
+                            | Action::ToggleColorMath
+                            | Action::ToggleWindows
+                                if is_pressed =>
+                            {
+                                let (name, enabled) =
+                                    toggle_layer(snes.ppu.layer_mask_mut(), action);
+                                if options.verbose {
+                                    let state = if enabled { "enabled" } else { "disabled" };
+                                    println!("[info] {name} {state}");
+                                }
+                            }
+                            Action::ToggleVoice(voice) if is_pressed => {
+                                voice_mask.mute ^= 1 << voice;
+                                snes.smp.set_voice_mask(voice_mask);
+                                if options.verbose {
+                                    let state = if voice_mask.mute & (1 << voice) > 0 {
+                                        "muted"
+                                    } else {
+                                        "unmuted"
+                                    };
+                                    println!("[info] voice {voice} {state}");
+                                }
+                            }
+                            Action::SoloVoice(voice) if is_pressed => {
+                                voice_mask.solo ^= 1 << voice;
+                                snes.smp.set_voice_mask(voice_mask);
+                                if options.verbose {
+                                    println!("[info] soloed voices: {:#010b}", voice_mask.solo);
+                                }
+                            }
+                            Action::PrintVoices if is_pressed => match snes.smp.get_spc() {
+                                Ok(spc) => print_voices(&spc, snes.smp.voice_mask()),
+                                Err(err) => eprintln!("[warning] could not get voices ({err})"),
+                            },
+                            action => speed.handle_action(action, is_pressed),
                         }
                     }
                 }
@@ -554,19 +962,34 @@ fn main() {
             },
             Event::MainEventsCleared => {
                 let now = Instant::now();
-                if now >= next_device_update {
-                    snes.run_cycle::<MASTER_CYCLES_PER_TICK>();
-                    let mut cycle_count = u64::from(MASTER_CYCLES_PER_TICK);
+                let speed_factor = speed.factor(fast_forward_speed, slow_motion_speed);
+                audio_muted.store(speed_factor != Some(1.0), Ordering::Relaxed);
+                let run_frame = if speed.paused {
+                    next_device_update = now;
+                    core::mem::take(&mut speed.frame_advance)
+                } else {
+                    now >= next_device_update
+                };
+                if run_frame {
+                    let mut cycle_count =
+                        u64::from(snes.run_until_event::<MASTER_CYCLES_PER_TICK>());
                     while !snes.new_frame {
-                        snes.run_cycle::<MASTER_CYCLES_PER_TICK>();
-                        cycle_count += u64::from(MASTER_CYCLES_PER_TICK)
+                        cycle_count += u64::from(snes.run_until_event::<MASTER_CYCLES_PER_TICK>())
                     }
                     // a more precise calculation is not possible by using floats
-                    next_device_update += Duration::from_nanos((8800 * cycle_count) / 189);
+                    let frame_time = Duration::from_nanos((8800 * cycle_count) / 189);
+                    match speed_factor {
+                        Some(factor) => next_device_update += frame_time.div_f64(factor),
+                        None => next_device_update = now,
+                    }
                     // reset the next update timer if it fell to far behind
                     if now > next_device_update + TIME_UNTIL_TIMER_RESET {
                         next_device_update = now;
                     }
+                    if let Some(err) = snes.smp.error() {
+                        eprintln!("[error] {err}");
+                        *control_flow = ControlFlow::Exit;
+                    }
                 }
                 let now = Instant::now();
                 if now >= next_graphics_update {
@@ -578,19 +1001,33 @@ fn main() {
                 match surf.get_current_texture() {
                     Ok(surface_texture) => {
                         if snes.ppu.frame_buffer.1 {
+                            let frame_buffer = &snes.ppu.frame_buffer;
+                            if (frame_buffer.width(), frame_buffer.height())
+                                != (screen_texture.extent.width, screen_texture.extent.height)
+                            {
+                                screen_texture = ScreenTexture::new(
+                                    &device,
+                                    &bind_group_layout,
+                                    &sampler,
+                                    &screen_size_buffer,
+                                    frame_buffer,
+                                );
+                            }
+                            let extent = screen_texture.extent;
                             queue.write_texture(
-                                texture.as_image_copy(),
-                                snes.ppu.frame_buffer.get_bytes(),
+                                screen_texture.texture.as_image_copy(),
+                                frame_pixels(
+                                    &snes.ppu.frame_buffer,
+                                    color_pipeline.as_ref(),
+                                    &mut converted_frame,
+                                )
+                                .as_flattened(),
                                 wgpu::ImageDataLayout {
                                     offset: 0,
-                                    bytes_per_row: core::num::NonZeroU32::new(
-                                        4 * texture_extent.width,
-                                    ),
-                                    rows_per_image: core::num::NonZeroU32::new(
-                                        texture_extent.height,
-                                    ),
+                                    bytes_per_row: core::num::NonZeroU32::new(4 * extent.width),
+                                    rows_per_image: core::num::NonZeroU32::new(extent.height),
                                 },
-                                texture_extent,
+                                extent,
                             );
                             if core::mem::take(&mut update_screen_size) {
                                 queue.write_buffer(
@@ -635,7 +1072,7 @@ fn main() {
                             depth_stencil_attachment: None,
                         });
                         rpass.set_pipeline(&render_pipeline);
-                        rpass.set_bind_group(0, &bind_group, &[]);
+                        rpass.set_bind_group(0, &screen_texture.bind_group, &[]);
                         rpass.draw(0..6, 0..1);
                         drop(rpass);
                         queue.submit(Some(encoder.finish()));
@@ -649,6 +1086,10 @@ fn main() {
                     Err(err) => error!("Failed to acquire next swap chain texture ({})", err),
                 };
             }
+            Event::LoopDestroyed => {
+                snes.smp.flush_samples_and_wait();
+                stop_recording(&recording);
+            }
             _ => (),
         }
     })
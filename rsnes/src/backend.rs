@@ -3,40 +3,133 @@ mod audio {
 
     pub trait AudioBackend: Send + 'static {
         fn push_sample(&mut self, sample: StereoSample);
+
+        /// Push the samples collected by the S-SMP, usually once per frame
+        fn push_samples(&mut self, samples: &[StereoSample]) {
+            for sample in samples {
+                self.push_sample(*sample)
+            }
+        }
+
+        /// Push the outputs of the single S-DSP voices, after enabling it with
+        /// [`crate::smp::Smp::set_voice_recording`]. Every batch gets pushed
+        /// right before the samples of the same period to [`AudioBackend::push_samples`].
+        fn push_voice_samples(&mut self, _samples: &[[StereoSample; 8]]) {}
     }
     pub struct Dummy;
 
     impl AudioBackend for Dummy {
         fn push_sample(&mut self, _sample: StereoSample) {}
+
+        fn push_samples(&mut self, _samples: &[StereoSample]) {}
     }
 }
 
 pub use audio::{AudioBackend, Dummy as AudioDummy};
 
+/// The layout of the pixels, the PPU writes to a [`FrameBuffer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// RGBA with 8 bits per channel and the brightness already applied
+    Rgba8,
+    /// The exact console color as `[bgr555 low byte, bgr555 high byte, brightness, 0]`,
+    /// where the brightness is in the range 0-15 (see [`crate::color`])
+    Bgr555,
+}
+
 pub trait FrameBuffer {
+    /// Select the format of the pixels, the PPU writes to this frame buffer
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Rgba8
+    }
     fn pixels(&self) -> &[[u8; 4]];
     fn mut_pixels(&mut self) -> &mut [[u8; 4]];
     fn request_redraw(&mut self);
+    /// Set the number of pixels per line of the current frame.
+    /// This is either [`ppu::SCREEN_WIDTH`] or [`ppu::MAX_SCREEN_WIDTH`] (hi-res)
+    /// or a multiple of [`ppu::SCREEN_WIDTH`] with HD Mode 7.
+    /// The default implementation ignores the width.
+    fn set_width(&mut self, _width: u32) {}
+    /// Set the number of lines of the current frame.
+    /// Interlaced frames have twice as many lines (448 or 478),
+    /// frames with HD Mode 7 are scaled by the Mode 7 scale.
+    /// The default implementation ignores the height.
+    fn set_height(&mut self, _height: u32) {}
+    /// Get the color (BGR555) of the external video at pixel `x` of line `y`.
+    ///
+    /// While external sync (super-impose, bit 7 of SETINI) is enabled, the
+    /// external picture is shown instead of the main screen backdrop.
+    /// `None` shows the backdrop color, like a console without external video.
+    fn external_pixel(&mut self, _x: u8, _y: u16) -> Option<u16> {
+        None
+    }
 }
 
-pub const FRAME_BUFFER_SIZE: usize = (ppu::MAX_SCREEN_HEIGHT_OVERSCAN * ppu::SCREEN_WIDTH) as usize;
+/// The number of pixels of the largest frame, which is a frame with HD Mode 7
+pub const FRAME_BUFFER_SIZE: usize = (ppu::MAX_SCREEN_HEIGHT_OVERSCAN
+    * ppu::SCREEN_WIDTH
+    * ppu::MAX_MODE7_SCALE as u32
+    * ppu::MAX_MODE7_SCALE as u32) as usize;
 use crate::ppu;
-#[derive(Debug, Clone)]
-pub struct ArrayFrameBuffer(pub [[u8; 4]; FRAME_BUFFER_SIZE], pub bool);
+#[derive(Debug, Clone, save_state_macro::DefaultByNew)]
+pub struct ArrayFrameBuffer(
+    pub Box<[[u8; 4]; FRAME_BUFFER_SIZE]>,
+    pub bool,
+    pub u32,
+    pub u32,
+    pub PixelFormat,
+);
 
 impl FrameBuffer for ArrayFrameBuffer {
+    fn pixel_format(&self) -> PixelFormat {
+        self.4
+    }
     fn pixels(&self) -> &[[u8; 4]] {
-        &self.0
+        &self.0[..]
     }
     fn mut_pixels(&mut self) -> &mut [[u8; 4]] {
-        &mut self.0
+        &mut self.0[..]
     }
     fn request_redraw(&mut self) {
         self.1 = true
     }
+    fn set_width(&mut self, width: u32) {
+        self.2 = width
+    }
+    fn set_height(&mut self, height: u32) {
+        self.3 = height
+    }
 }
 
 impl ArrayFrameBuffer {
+    pub fn new() -> Self {
+        // allocated on the heap directly, the buffer is too large for the stack
+        let pixels = vec![[0; 4]; FRAME_BUFFER_SIZE].into_boxed_slice();
+        Self(
+            pixels.try_into().unwrap(),
+            true,
+            ppu::SCREEN_WIDTH,
+            ppu::MAX_SCREEN_HEIGHT,
+            PixelFormat::Rgba8,
+        )
+    }
+
+    /// Receive raw console colors instead of RGBA (see [`PixelFormat::Bgr555`])
+    pub fn with_pixel_format(mut self, format: PixelFormat) -> Self {
+        self.4 = format;
+        self
+    }
+
+    /// The number of pixels per line of the current frame
+    pub const fn width(&self) -> u32 {
+        self.2
+    }
+
+    /// The number of lines of the current frame
+    pub const fn height(&self) -> u32 {
+        self.3
+    }
+
     pub fn get_bytes(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self.0.as_ptr() as _, self.0.len() << 2) }
     }
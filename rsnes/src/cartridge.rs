@@ -9,10 +9,11 @@ use std::convert::TryInto;
 
 use crate::{
     device::{Addr24, Data},
-    enhancement::{sa1::Sa1, Dsp, DspVersion},
+    enhancement::{msu1::Msu1, sa1::Sa1, Dsp, DspVersion},
+    spc700::StereoSample,
     timing::Cycles,
 };
-use save_state::{SaveStateDeserializer, SaveStateSerializer};
+use save_state::{InSaveState, SaveStateDeserializer, SaveStateSerializer};
 use save_state_macro::*;
 
 const MINIMUM_SIZE: usize = 0x8000;
@@ -481,9 +482,61 @@ impl Default for MappingEntry {
     }
 }
 
+/// Size of a page in the memory mapping lookup table
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+const PAGE_COUNT: usize = 1 << (24 - PAGE_SHIFT);
+
+/// A precomputed memory mapping for a single page of the 24-bit address space
+#[derive(Debug, Clone, Copy, InSaveState)]
+struct Page {
+    mapped: bool,
+    base: u32,
+    addr_mask: u16,
+    read: ReadFunction,
+    write: WriteFunction,
+}
+
+impl Page {
+    const UNMAPPED: Self = Self {
+        mapped: false,
+        base: 0,
+        addr_mask: 0,
+        read: ReadFunction::Rom,
+        write: WriteFunction::Ignore,
+    };
+
+    fn new(entry: &MappingEntry, page_addr: Addr24) -> Self {
+        Self {
+            mapped: true,
+            base: entry.map.run(page_addr),
+            addr_mask: entry.map.addr_mask & (PAGE_SIZE - 1) as u16,
+            read: entry.read,
+            write: entry.write,
+        }
+    }
+
+    const fn index(&self, addr: u16) -> u32 {
+        self.base | (addr & self.addr_mask) as u32
+    }
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self::UNMAPPED
+    }
+}
+
+const fn page_id(addr: Addr24) -> usize {
+    ((addr.bank as usize) << (16 - PAGE_SHIFT)) | (addr.addr as usize >> PAGE_SHIFT)
+}
+
 #[derive(Debug, Default, Clone, InSaveState)]
 pub struct MemoryMapping {
     areas: Vec<MappingEntry>,
+    /// Derived from `areas`, it gets rebuilt by [`Cartridge::deserialize_mapping`]
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    pages: Vec<Page>,
 }
 
 macro_rules! map {
@@ -502,14 +555,38 @@ macro_rules! map {
 }
 
 impl MemoryMapping {
-    pub fn find(&self, addr: Addr24) -> Option<(u32, &MappingEntry)> {
-        self.areas.iter().find_map(|entry| {
-            if entry.area.find(addr) {
-                Some((entry.map.run(addr), entry))
-            } else {
-                None
+    /// Precompute the page table from the mapped areas.
+    ///
+    /// All areas must start and end on page boundaries.
+    /// If areas overlap, the first one takes precedence.
+    fn build_pages(&mut self) {
+        self.pages = vec![Page::UNMAPPED; PAGE_COUNT];
+        for entry in self.areas.iter().rev() {
+            let Area { start, end } = entry.area;
+            assert!(
+                u32::from(start.addr) % PAGE_SIZE == 0
+                    && (u32::from(end.addr) + 1) % PAGE_SIZE == 0,
+                "memory mapping area {:?} is not aligned to pages",
+                entry
+            );
+            for bank in start.bank..=end.bank {
+                for addr in
+                    (u32::from(start.addr)..=u32::from(end.addr)).step_by(PAGE_SIZE as usize)
+                {
+                    let addr = Addr24::new(bank, addr as u16);
+                    self.pages[page_id(addr)] = Page::new(entry, addr);
+                }
             }
-        })
+        }
+    }
+
+    fn find_page(&self, addr: Addr24) -> Option<&Page> {
+        self.pages.get(page_id(addr)).filter(|page| page.mapped)
+    }
+
+    pub fn find(&self, addr: Addr24) -> Option<(u32, ReadFunPointer, WriteFunPointer)> {
+        self.find_page(addr)
+            .map(|page| (page.index(addr.addr), page.read.get(), page.write.get()))
     }
 }
 
@@ -553,6 +630,9 @@ pub struct Cartridge {
     ram: Vec<u8>,
     dsp: Option<Dsp>,
     sa1: Option<Sa1>,
+    #[except(Self::serialize_msu1, Self::deserialize_msu1)]
+    msu1: Option<Msu1>,
+    #[except(InSaveState::serialize, Self::deserialize_mapping)]
     mapping: MemoryMapping,
 }
 
@@ -614,10 +694,12 @@ impl Cartridge {
             mapping: MemoryMapping::default(),
             dsp,
             sa1,
+            msu1: None,
             header,
         };
 
         slf.setup_memory_mappings();
+        slf.mapping.build_pages();
 
         Ok(slf)
     }
@@ -690,24 +772,29 @@ impl Cartridge {
     }
 
     pub fn read_byte(&mut self, addr: Addr24) -> Option<u8> {
+        if let Some(msu1) = &mut self.msu1 {
+            if Msu1::is_mapped(addr.bank, addr.addr) {
+                return Some(msu1.read(addr.addr));
+            }
+        }
         if self.has_sa1() {
             self.sa1_read::<false>(addr)
         } else {
-            if let Some((index, MappingEntry { read, .. })) = self.mapping.find(addr) {
-                Some(read.get()(self, index))
-            } else {
-                None
-            }
+            let (index, read, _) = self.mapping.find(addr)?;
+            Some(read(self, index))
         }
     }
 
     pub fn write_byte(&mut self, addr: Addr24, val: u8) {
+        if let Some(msu1) = &mut self.msu1 {
+            if Msu1::is_mapped(addr.bank, addr.addr) {
+                return msu1.write(addr.addr, val);
+            }
+        }
         if self.has_sa1() {
             self.sa1_write::<false>(addr, val)
-        } else {
-            if let Some((index, MappingEntry { write, .. })) = self.mapping.find(addr) {
-                write.get()(self, index, val)
-            }
+        } else if let Some((index, _, write)) = self.mapping.find(addr) {
+            write(self, index, val)
         }
     }
 
@@ -777,9 +864,25 @@ impl Cartridge {
 
     fn ignore_write(&mut self, _addr: u32, _val: u8) {}
 
+    /// Test if all bytes of a `size`-byte access can be served by a single page lookup
+    fn is_single_page_access(&self, addr: Addr24, size: usize) -> bool {
+        !self.has_sa1()
+            && (self.msu1.is_none() || !Msu1::is_mapped(addr.bank, addr.addr))
+            && (u32::from(addr.addr) & (PAGE_SIZE - 1)) as usize + size <= PAGE_SIZE as usize
+    }
+
     /// Read from the cartridge
     pub fn read<D: Data>(&mut self, mut addr: Addr24) -> Option<D> {
         let mut arr: D::Arr = Default::default();
+        if self.is_single_page_access(addr, arr.as_ref().len()) {
+            let page = *self.mapping.find_page(addr)?;
+            let read = page.read.get();
+            for v in arr.as_mut() {
+                *v = read(self, page.index(addr.addr));
+                addr.addr = addr.addr.wrapping_add(1);
+            }
+            return Some(D::from_bytes(&arr));
+        }
         let mut open_bus = None;
         for v in arr.as_mut() {
             *v = self.read_byte(addr).or(open_bus)?;
@@ -791,7 +894,18 @@ impl Cartridge {
 
     /// Write to the cartridge
     pub fn write<D: Data>(&mut self, mut addr: Addr24, value: D) {
-        for &v in value.to_bytes().as_ref().iter() {
+        let bytes = value.to_bytes();
+        if self.is_single_page_access(addr, bytes.as_ref().len()) {
+            if let Some(page) = self.mapping.find_page(addr).copied() {
+                let write = page.write.get();
+                for &v in bytes.as_ref().iter() {
+                    write(self, page.index(addr.addr), v);
+                    addr.addr = addr.addr.wrapping_add(1);
+                }
+            }
+            return;
+        }
+        for &v in bytes.as_ref().iter() {
             self.write_byte(addr, v);
             addr.addr = addr.addr.wrapping_add(1);
         }
@@ -808,12 +922,46 @@ impl Cartridge {
         if let Some(sa1) = &mut self.sa1 {
             sa1.set_region(pal)
         }
+        if let Some(msu1) = &mut self.msu1 {
+            msu1.set_region(pal)
+        }
+    }
+
+    /// Reset all coprocessors. Cartridge RAM keeps its contents.
+    pub fn reset(&mut self) {
+        if let Some(dsp) = &mut self.dsp {
+            dsp.reset()
+        }
+        if let Some(sa1) = &mut self.sa1 {
+            sa1.reset()
+        }
+        if let Some(msu1) = &mut self.msu1 {
+            msu1.reset()
+        }
+    }
+
+    /// Reinitialize all coprocessors. Battery-backed RAM keeps its contents.
+    ///
+    /// The region needs to be set again afterwards.
+    pub fn power_cycle(&mut self) {
+        if let Some(dsp) = &mut self.dsp {
+            *dsp = Dsp::new(dsp.version())
+        }
+        if let Some(sa1) = &mut self.sa1 {
+            sa1.power_cycle()
+        }
+        if let Some(msu1) = &mut self.msu1 {
+            msu1.reset()
+        }
     }
 
     pub fn tick(&mut self, n: Cycles) {
         if let Some(dsp) = &mut self.dsp {
             dsp.tick(n)
         }
+        if let Some(msu1) = &mut self.msu1 {
+            msu1.tick(n)
+        }
     }
 
     pub fn refresh_coprocessors(&mut self) {
@@ -837,4 +985,51 @@ impl Cartridge {
             .as_mut()
             .expect("unexpectedly queried sa1-chip in a non-sa1 cartridge")
     }
+
+    /// Connect a MSU-1 interface to this cartridge
+    pub fn attach_msu1(&mut self, msu1: Msu1) {
+        self.msu1 = Some(msu1)
+    }
+
+    pub fn has_msu1(&self) -> bool {
+        self.msu1.is_some()
+    }
+
+    /// Take the audio samples the MSU-1 generated since the last call
+    pub fn take_msu1_samples(&mut self) -> Option<Vec<StereoSample>> {
+        self.msu1
+            .as_mut()
+            .map(Msu1::take_samples)
+            .filter(|samples| !samples.is_empty())
+    }
+
+    fn serialize_msu1(msu1: &Option<Msu1>, ser: &mut SaveStateSerializer) {
+        msu1.is_some().serialize(ser);
+        if let Some(msu1) = msu1 {
+            msu1.serialize(ser)
+        }
+    }
+
+    fn deserialize_mapping(mapping: &mut MemoryMapping, deser: &mut SaveStateDeserializer) {
+        mapping.deserialize(deser);
+        mapping.build_pages();
+    }
+
+    fn deserialize_msu1(msu1: &mut Option<Msu1>, deser: &mut SaveStateDeserializer) {
+        // the data and audio files are not part of the save state,
+        // so the state is only applied to an already attached MSU-1
+        let mut is_some = false;
+        is_some.deserialize(deser);
+        if is_some {
+            if let Some(msu1) = msu1 {
+                msu1.deserialize(deser);
+                msu1.reload_track();
+            } else {
+                eprintln!(
+                    "warning: ignoring the MSU-1 state of a save state without a MSU-1 interface"
+                );
+                Msu1::detached().deserialize(deser);
+            }
+        }
+    }
 }
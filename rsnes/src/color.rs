@@ -0,0 +1,110 @@
+//! Conversion of exact console colors to RGB for displaying
+//!
+//! The PPU outputs 15-bit BGR colors and a brightness of 0-15 as an analog
+//! signal, which looked different on the displays of the time than a direct
+//! conversion to sRGB does. A [`ColorPipeline`] converts the pixels of a frame
+//! buffer with [`PixelFormat::Bgr555`](crate::backend::PixelFormat::Bgr555)
+//! using a gamma curve and a color-correction profile.
+//!
+//! # Literature
+//!
+//! - <https://www.itu.int/rec/R-REC-BT.1886>
+//! - <https://en.wikipedia.org/wiki/SRGB>
+
+use crate::ppu::Color;
+
+/// Gamma of the sRGB output
+const OUTPUT_GAMMA: f32 = 2.2;
+
+/// A color-correction profile, imitating a type of display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorProfile {
+    /// The same conversion as the PPU does for RGBA frame buffers
+    Direct,
+    /// A CRT television with a gamma of 2.4 (BT.1886) and NTSC (SMPTE-C) phosphors
+    Crt,
+    /// A low-contrast handheld LCD with washed-out colors
+    Lcd,
+}
+
+impl ColorProfile {
+    /// The exponent, which converts the signal to linear light
+    const fn input_gamma(&self) -> f32 {
+        match self {
+            Self::Direct => OUTPUT_GAMMA,
+            Self::Crt => 2.4,
+            Self::Lcd => 2.0,
+        }
+    }
+
+    /// The conversion of linear light to linear sRGB
+    const fn matrix(&self) -> [[f32; 3]; 3] {
+        match self {
+            Self::Direct => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            Self::Crt => [
+                [0.9395, 0.0502, 0.0103],
+                [0.0178, 0.9658, 0.0164],
+                [-0.0016, -0.0044, 1.0060],
+            ],
+            Self::Lcd => [
+                [0.80, 0.135, 0.065],
+                [0.10, 0.80, 0.10],
+                [0.065, 0.135, 0.80],
+            ],
+        }
+    }
+}
+
+/// Convert raw pixels `[bgr555 low byte, bgr555 high byte, brightness, 0]` to RGBA
+#[derive(Debug, Clone)]
+pub struct ColorPipeline {
+    /// The RGBA colors indexed by `(brightness << 15) | bgr555`
+    table: Vec<[u8; 4]>,
+}
+
+impl ColorPipeline {
+    /// Create a conversion with a color-correction `profile` and an
+    /// additional `gamma` correction of the output.
+    ///
+    /// A `gamma` of 1.0 keeps the curve of the profile, larger values brighten dark colors.
+    pub fn new(profile: ColorProfile, gamma: f32) -> Self {
+        let matrix = profile.matrix();
+        let table = (0..16u8)
+            .flat_map(|brightness| (0..0x8000u16).map(move |bgr| (brightness, bgr)))
+            .map(|(brightness, bgr)| {
+                let color = Color::from(bgr);
+                if profile == ColorProfile::Direct && gamma == 1.0 {
+                    return color.to_rgba8_with_brightness(brightness);
+                }
+                if brightness == 0 {
+                    return [0, 0, 0, 255];
+                }
+                let signal = color
+                    .to_rgb5()
+                    .map(|c| f32::from(c) / 31.0 * f32::from(brightness) / 15.0);
+                let linear = signal.map(|c| c.powf(profile.input_gamma()));
+                let mut rgba = [0, 0, 0, 255];
+                for (out, row) in rgba.iter_mut().zip(matrix) {
+                    let value = row.iter().zip(linear).map(|(m, c)| m * c).sum::<f32>();
+                    let value = value.clamp(0.0, 1.0).powf(1.0 / (OUTPUT_GAMMA * gamma));
+                    *out = (value * 255.0).round() as u8;
+                }
+                rgba
+            })
+            .collect();
+        Self { table }
+    }
+
+    /// Convert a single raw pixel to RGBA
+    pub fn convert(&self, pixel: [u8; 4]) -> [u8; 4] {
+        let bgr = usize::from(u16::from_le_bytes([pixel[0], pixel[1]]) & 0x7fff);
+        self.table[(usize::from(pixel[2] & 15) << 15) | bgr]
+    }
+
+    /// Convert the raw pixels `src` to RGBA pixels in `dst`
+    pub fn convert_frame(&self, src: &[[u8; 4]], dst: &mut [[u8; 4]]) {
+        for (dst, src) in dst.iter_mut().zip(src) {
+            *dst = self.convert(*src)
+        }
+    }
+}
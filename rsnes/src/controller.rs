@@ -214,6 +214,13 @@ impl ControllerPorts {
         }
     }
 
+    /// Reset the I/O-port and the auto joypad read.
+    /// The connected controllers are kept.
+    pub fn reset(&mut self) {
+        self.pio = 0;
+        self.auto_joypad_timer = 0;
+    }
+
     /// Write to the programmable I/O-port.
     /// Returns if EXTLATCH shall be triggered.
     pub fn set_pio(&mut self, val: u8) -> bool {
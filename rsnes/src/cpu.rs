@@ -190,6 +190,24 @@ impl Cpu {
         }
     }
 
+    /// Reset the processor like a pulse on the /RESET pin does.
+    ///
+    /// The accumulator and the low bytes of the index registers and
+    /// the stack pointer keep their values.
+    pub fn reset(&mut self) {
+        let regs = &self.regs;
+        *self = Self {
+            regs: Regs {
+                a: regs.a,
+                x: regs.x & 0xff,
+                y: regs.y & 0xff,
+                sp: 0x100 | (regs.sp & 0xff),
+                ..Self::new().regs
+            },
+            ..Self::new()
+        }
+    }
+
     /// Indicate if the A register is in 8-bit mode
     pub const fn is_reg8(&self) -> bool {
         self.regs.status.has(Status::ACCUMULATION) || self.regs.is_emulation
@@ -221,6 +221,61 @@ impl<B: AudioBackend, FB: FrameBuffer> Device<B, FB> {
         self.reset_program_counter();
     }
 
+    /// Press the reset button.
+    ///
+    /// This pulses /RESET on the CPU, the PPU, the APU and the cartridge.
+    /// WRAM, VRAM, audio RAM and cartridge RAM keep their contents.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.dma.reset();
+        self.ppu.reset();
+        self.smp.reset();
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.reset();
+        }
+        self.reset_internal_state();
+        if self.cartridge.is_some() {
+            self.reset_program_counter();
+        }
+    }
+
+    /// Turn the console off and on again.
+    ///
+    /// Only battery-backed cartridge RAM keeps its contents.
+    pub fn power_cycle(&mut self) {
+        self.cpu = Cpu::new();
+        self.dma = Dma::new();
+        self.ppu.power_cycle();
+        self.smp.power_cycle();
+        self.ram = [0; RAM_SIZE];
+        self.wram_addr.set(0);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.power_cycle();
+            cartridge.set_region(self.is_pal);
+        }
+        self.reset_internal_state();
+        if self.cartridge.is_some() {
+            self.reset_program_counter();
+        }
+    }
+
+    fn reset_internal_state(&mut self) {
+        self.controllers.reset();
+        self.open_bus = 0;
+        self.memory_cycles = 0;
+        self.cpu_ahead_cycles = 186;
+        self.new_scanline = true;
+        self.new_frame = true;
+        self.scanline_drawn = false;
+        self.do_hdma = true;
+        self.irq_time_h = 0x7fc;
+        self.irq_time_v = 0x1ff;
+        self.shall_irq = false;
+        self.shall_nmi = false;
+        self.nmi_vblank_bit.set(false);
+        self.math_registers = MathRegisters::new();
+    }
+
     pub fn reset_program_counter(&mut self) {
         let addr = crate::cpu::RESET_VECTOR_ADDR;
         self.cpu.regs.pc = Addr24::new(0, self.read::<u16>(addr));
@@ -106,6 +106,14 @@ impl Dma {
         }
     }
 
+    /// Stop all running transfers. The channel registers keep their values.
+    pub fn reset(&mut self) {
+        *self = Self {
+            channels: self.channels,
+            ..Self::new()
+        }
+    }
+
     /// Read 8-bit from channel transfer values
     pub fn read(&self, addr: u16) -> Option<u8> {
         let channel = (addr >> 4) & 0b111;
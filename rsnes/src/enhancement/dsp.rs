@@ -147,6 +147,15 @@ impl Dsp {
         }
     }
 
+    /// Reset the processor state. The data RAM keeps its contents.
+    pub fn reset(&mut self) {
+        *self = Self {
+            ram: self.ram,
+            timing_proportion: self.timing_proportion,
+            ..Self::new(self.ver)
+        }
+    }
+
     pub const fn version(&self) -> DspVersion {
         self.ver
     }
@@ -1,4 +1,5 @@
 mod dsp;
+pub mod msu1;
 pub mod sa1;
 
 #[doc(inline)]
@@ -0,0 +1,390 @@
+//! MSU-1 enhanced audio and data streaming
+//!
+//! The MSU-1 is not a real chip, but a well-established interface for
+//! streaming CD-quality audio and large data files. It is enabled by
+//! placing a `<rom>.msu` data file and `<rom>-<n>.pcm` audio tracks
+//! next to the cartridge file.
+//!
+//! # Literature
+//!
+//! - <https://helmet.kafuka.org/msu1.htm>
+//! - <https://sd2snes.de/blog/cool-stuff/msu1>
+
+use crate::{
+    spc700::StereoSample,
+    timing::{Cycles, MSU1_CPU_TIMING_PROPORTION_NTSC, MSU1_CPU_TIMING_PROPORTION_PAL},
+};
+use save_state_macro::InSaveState;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const IDENTIFIER: [u8; 6] = *b"S-MSU1";
+const REVISION: u8 = 1;
+const PCM_MAGIC: [u8; 4] = *b"MSU1";
+const PCM_HEADER_SIZE: usize = 8;
+const PCM_SAMPLE_RATE: u32 = 44100;
+const OUTPUT_SAMPLE_RATE: u32 = 32000;
+/// Number of samples, which are read from an audio track at once
+const STREAM_CHUNK_SAMPLES: u32 = 0x1000;
+
+/// Bits of the status register.
+///
+/// The data file is held in memory and audio tracks are opened right
+/// on selection, so the data busy (bit 7) and audio busy (bit 6) flags
+/// are never set.
+pub mod status {
+    pub const AUDIO_REPEAT: u8 = 0x20;
+    pub const AUDIO_PLAYING: u8 = 0x10;
+    pub const TRACK_MISSING: u8 = 0x08;
+}
+
+/// An audio track, which is streamed from disk in chunks
+#[derive(Debug, Clone)]
+struct PcmTrack {
+    file: Arc<File>,
+    loop_point: u32,
+    /// number of samples
+    len: u32,
+    chunk_start: u32,
+    chunk: Vec<StereoSample>,
+}
+
+impl PcmTrack {
+    fn open(path: &Path) -> Option<Self> {
+        let mut file = File::open(path).ok()?;
+        let mut header = [0; PCM_HEADER_SIZE];
+        file.read_exact(&mut header).ok()?;
+        if header[..4] != PCM_MAGIC {
+            return None;
+        }
+        let loop_point = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let len = file
+            .metadata()
+            .ok()?
+            .len()
+            .saturating_sub(PCM_HEADER_SIZE as u64)
+            / 4;
+        let mut track = Self {
+            file: Arc::new(file),
+            loop_point,
+            len: len.try_into().unwrap_or(u32::MAX),
+            chunk_start: 0,
+            chunk: vec![],
+        };
+        track.load_chunk(0);
+        Some(track)
+    }
+
+    /// Read the chunk of samples starting at `start` from disk
+    fn load_chunk(&mut self, start: u32) {
+        let count = STREAM_CHUNK_SAMPLES.min(self.len.saturating_sub(start));
+        let mut bytes = vec![0; count as usize * 4];
+        let mut file = &*self.file;
+        let offset = PCM_HEADER_SIZE as u64 + u64::from(start) * 4;
+        if let Err(err) = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut bytes))
+        {
+            eprintln!("warning: could not read MSU-1 audio track ({err})");
+            bytes.fill(0);
+        }
+        self.chunk_start = start;
+        self.chunk = bytes
+            .chunks_exact(4)
+            .map(|s| {
+                StereoSample::<i16>::new(
+                    i16::from_le_bytes([s[0], s[1]]),
+                    i16::from_le_bytes([s[2], s[3]]),
+                )
+            })
+            .collect();
+    }
+
+    fn get(&mut self, position: u32) -> StereoSample {
+        if position >= self.len {
+            return StereoSample::default();
+        }
+        let index = position.wrapping_sub(self.chunk_start) as usize;
+        if index >= self.chunk.len() {
+            self.load_chunk(position);
+            return self.chunk[0];
+        }
+        self.chunk[index]
+    }
+}
+
+#[derive(Debug, Clone, InSaveState)]
+pub struct Msu1 {
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    base_path: PathBuf,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    data: Vec<u8>,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    pcm: Option<PcmTrack>,
+    data_seek: u32,
+    data_offset: u32,
+    track_latch: u16,
+    track: Option<u16>,
+    track_missing: bool,
+    loop_point: u32,
+    volume: u8,
+    playing: bool,
+    repeat: bool,
+    position: u32,
+    /// fractional sample position in units of 1/`OUTPUT_SAMPLE_RATE`
+    position_fraction: u32,
+    timing_proportion: (Cycles, Cycles),
+    master_cycles: Cycles,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    output: Vec<StereoSample>,
+}
+
+impl Msu1 {
+    /// Create a MSU-1 interface, when the data file `<rom>.msu` exists
+    /// next to the ROM file at `rom_path`.
+    pub fn open<P: AsRef<Path>>(rom_path: P) -> std::io::Result<Option<Self>> {
+        let data_path = rom_path.as_ref().with_extension("msu");
+        if !data_path.is_file() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&data_path)?;
+        Ok(Some(Self {
+            base_path: rom_path.as_ref().with_extension(""),
+            data,
+            ..Self::detached()
+        }))
+    }
+
+    /// Create a MSU-1 interface without data file and audio tracks
+    pub(crate) fn detached() -> Self {
+        Self {
+            base_path: PathBuf::new(),
+            data: vec![],
+            pcm: None,
+            data_seek: 0,
+            data_offset: 0,
+            track_latch: 0,
+            track: None,
+            track_missing: false,
+            loop_point: 0,
+            volume: 0xff,
+            playing: false,
+            repeat: false,
+            position: 0,
+            position_fraction: 0,
+            timing_proportion: MSU1_CPU_TIMING_PROPORTION_NTSC,
+            master_cycles: 0,
+            output: vec![],
+        }
+    }
+
+    /// Reset all registers and stop the audio playback
+    pub fn reset(&mut self) {
+        self.data_seek = 0;
+        self.data_offset = 0;
+        self.track_latch = 0;
+        self.track = None;
+        self.volume = 0xff;
+        self.playing = false;
+        self.repeat = false;
+        self.position = 0;
+        self.position_fraction = 0;
+        self.master_cycles = 0;
+        self.output.clear();
+        self.reload_track();
+    }
+
+    pub fn set_region(&mut self, is_pal: bool) {
+        self.timing_proportion = if is_pal {
+            MSU1_CPU_TIMING_PROPORTION_PAL
+        } else {
+            MSU1_CPU_TIMING_PROPORTION_NTSC
+        }
+    }
+
+    /// Test if the MSU-1 interface is mapped to this address
+    pub const fn is_mapped(bank: u8, addr: u16) -> bool {
+        bank & 0x40 == 0 && addr & 0xfff8 == 0x2000
+    }
+
+    fn track_path(&self, track: u16) -> PathBuf {
+        let mut name = self.base_path.as_os_str().to_owned();
+        name.push(format!("-{}.pcm", track));
+        name.into()
+    }
+
+    fn select_track(&mut self, track: u16) {
+        self.playing = false;
+        self.repeat = false;
+        self.position = 0;
+        self.position_fraction = 0;
+        self.track = Some(track);
+        self.reload_track();
+    }
+
+    /// Open the currently selected track for streaming its samples from disk.
+    ///
+    /// This needs to be called after deserializing a save state.
+    pub fn reload_track(&mut self) {
+        self.pcm = self
+            .track
+            .and_then(|track| PcmTrack::open(&self.track_path(track)));
+        self.track_missing = self.track.is_some() && self.pcm.is_none();
+        self.loop_point = self.pcm.as_ref().map_or(0, |pcm| pcm.loop_point);
+    }
+
+    fn track_len(&self) -> u32 {
+        self.pcm.as_ref().map_or(0, |pcm| pcm.len)
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr & 7 {
+            0 => {
+                let mut status = REVISION;
+                if self.repeat {
+                    status |= status::AUDIO_REPEAT
+                }
+                if self.playing {
+                    status |= status::AUDIO_PLAYING
+                }
+                if self.track_missing {
+                    status |= status::TRACK_MISSING
+                }
+                status
+            }
+            1 => {
+                let val = self
+                    .data
+                    .get(self.data_offset as usize)
+                    .copied()
+                    .unwrap_or(0);
+                self.data_offset = self.data_offset.wrapping_add(1);
+                val
+            }
+            n => IDENTIFIER[usize::from(n) - 2],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr & 7 {
+            n @ 0..=3 => {
+                let shift = u32::from(n) << 3;
+                self.data_seek = (self.data_seek & !(0xff << shift)) | (u32::from(val) << shift);
+                if n == 3 {
+                    self.data_offset = self.data_seek
+                }
+            }
+            4 => self.track_latch = (self.track_latch & 0xff00) | u16::from(val),
+            5 => {
+                self.track_latch = (self.track_latch & 0xff) | (u16::from(val) << 8);
+                self.select_track(self.track_latch)
+            }
+            6 => self.volume = val,
+            7 => {
+                if !self.track_missing {
+                    self.playing = val & 1 > 0;
+                    self.repeat = val & 2 > 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_sample(&mut self, position: u32) -> StereoSample {
+        self.pcm
+            .as_mut()
+            .map(|pcm| pcm.get(position))
+            .unwrap_or_default()
+    }
+
+    fn advance(&mut self) {
+        self.position += 1;
+        if self.position >= self.track_len() {
+            if self.repeat && self.loop_point < self.track_len() {
+                self.position = self.loop_point
+            } else {
+                self.playing = false;
+                self.position = 0;
+            }
+        }
+    }
+
+    /// Generate the next sample at the S-DSP sample rate
+    fn generate_sample(&mut self) -> StereoSample {
+        if !self.playing {
+            return StereoSample::default();
+        }
+        let next = if self.position + 1 < self.track_len() {
+            self.position + 1
+        } else if self.repeat {
+            self.loop_point
+        } else {
+            self.position
+        };
+        let (a, b) = (
+            self.get_sample(self.position).to32(),
+            self.get_sample(next).to32(),
+        );
+        let frac = self.position_fraction as i32;
+        let volume = i32::from(self.volume);
+        let sample = a
+            .zip_with(b, |a, b| {
+                let v = a + ((b - a) * frac) / OUTPUT_SAMPLE_RATE as i32;
+                (v * volume) / 0xff
+            })
+            .clamp16();
+        self.position_fraction += PCM_SAMPLE_RATE;
+        while self.playing && self.position_fraction >= OUTPUT_SAMPLE_RATE {
+            self.position_fraction -= OUTPUT_SAMPLE_RATE;
+            self.advance();
+        }
+        sample
+    }
+
+    /// Tick in main CPU master cycles
+    pub fn tick(&mut self, n: Cycles) {
+        self.master_cycles += n * self.timing_proportion.1;
+        while self.master_cycles >= self.timing_proportion.0 {
+            self.master_cycles -= self.timing_proportion.0;
+            let sample = self.generate_sample();
+            self.output.push(sample);
+        }
+    }
+
+    /// Take all samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<StereoSample> {
+        core::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_is_streamed_in_chunks() {
+        let len = STREAM_CHUNK_SAMPLES * 2 + 3;
+        let mut content = PCM_MAGIC.to_vec();
+        content.extend_from_slice(&7u32.to_le_bytes());
+        for i in 0..len as i16 {
+            content.extend_from_slice(&i.to_le_bytes());
+            content.extend_from_slice(&(-i).to_le_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("rsnes-msu1-{}.pcm", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let mut track = PcmTrack::open(&path).unwrap();
+        assert_eq!((track.len, track.loop_point), (len, 7));
+        assert_eq!(track.chunk.len(), STREAM_CHUNK_SAMPLES as usize);
+        for position in [0, 5, STREAM_CHUNK_SAMPLES + 1, len - 1, 2] {
+            let i = position as i16;
+            assert_eq!(track.get(position), StereoSample::<i16>::new(i, -i));
+        }
+        assert_eq!(track.get(len), StereoSample::default());
+        drop(track);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -441,9 +441,27 @@ impl Sa1 {
         self.timer.set_region(is_pal)
     }
 
+    /// Reset all registers. The SA-1 CPU is held in reset until
+    /// the SNES releases it via register $2200.
+    /// I-RAM and BW-RAM keep their contents.
     pub fn reset(&mut self) {
-        // TODO: correctly implement resetting
-        *self = Self::new()
+        *self = Self {
+            iram: self.iram,
+            bwram: self.bwram,
+            timer: Timer {
+                vend: self.timer.vend,
+                ..Timer::new()
+            },
+            ..Self::new()
+        }
+    }
+
+    /// Reinitialize the chip. Only the battery-backed BW-RAM keeps its contents.
+    pub fn power_cycle(&mut self) {
+        *self = Self {
+            bwram: self.bwram,
+            ..Self::new()
+        }
     }
 
     pub fn cpu_mut(&mut self) -> &mut Cpu {
@@ -0,0 +1,365 @@
+//! Decoding of the video memory for debugging and asset extraction
+//!
+//! All functions work on a shared reference to the [`Ppu`] and return
+//! RGBA images, so they may be used headlessly, e.g. for tile, tilemap
+//! and sprite viewers or for dumping graphics in tests.
+//! Transparent pixels (palette index 0) have an alpha value of 0.
+//!
+//! # Literature
+//!
+//! - <https://wiki.superfamicom.org/backgrounds>
+//! - <https://wiki.superfamicom.org/sprites>
+
+use crate::{
+    backend::FrameBuffer,
+    oam::{CgRam, Object},
+    ppu::{Color, Ppu, Vram},
+};
+
+/// An RGBA image with rows of `width` pixels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0; 4]; (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [u8; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, pixel: [u8; 4]) {
+        self.pixels[(y * self.width + x) as usize] = pixel
+    }
+}
+
+/// Get the size of a single tile with `bits` bits per pixel in VRAM words
+pub const fn tile_words(bits: u8) -> u16 {
+    (bits as u16) << 2
+}
+
+/// Decode the 8x8 palette indices of the tile at VRAM word address `addr`.
+///
+/// `bits` is the number of bits per pixel (2, 4 or 8). Panics otherwise.
+pub fn decode_tile(vram: &Vram, addr: u16, bits: u8) -> [[u8; 8]; 8] {
+    assert!(
+        matches!(bits, 2 | 4 | 8),
+        "unsupported tile depth of {bits} bits per pixel"
+    );
+    let mut tile = [[0; 8]; 8];
+    for (y, row) in tile.iter_mut().enumerate() {
+        for plane in 0..bits >> 1 {
+            let [low, high] = vram
+                .read(addr.wrapping_add(y as u16 | (u16::from(plane) << 3)))
+                .to_le_bytes();
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let bit = |byte: u8| (byte >> (7 - x)) & 1;
+                *pixel |= (bit(low) | (bit(high) << 1)) << (plane << 1);
+            }
+        }
+    }
+    tile
+}
+
+/// Convert a CGRAM color to full brightness RGBA
+fn cgram_color(cgram: &CgRam, addr: u8) -> [u8; 4] {
+    Color::from(cgram.read16(addr)).to_rgba8_with_brightness(15)
+}
+
+/// Get the color of a palette index with `palette_base` being the CGRAM
+/// address of the first palette entry
+fn palette_color(cgram: &CgRam, palette_base: u8, idx: u8) -> [u8; 4] {
+    if idx == 0 {
+        [0; 4]
+    } else {
+        cgram_color(cgram, palette_base.wrapping_add(idx))
+    }
+}
+
+/// Get all 256 CGRAM colors as RGBA
+pub fn cgram_palette(cgram: &CgRam) -> [[u8; 4]; 256] {
+    let mut colors = [[0; 4]; 256];
+    for (addr, color) in colors.iter_mut().enumerate() {
+        *color = cgram_color(cgram, addr as u8)
+    }
+    colors
+}
+
+/// Render `count` tiles beginning at VRAM word address `addr` in rows of `columns` tiles.
+///
+/// The tiles are colored with the palette `palette` of `1 << bits` colors.
+/// With `bits == 8` the whole CGRAM is used. A `columns` of 0 is treated as 1.
+///
+/// Panics, if `bits` is not 2, 4 or 8 or if the palette does not fit into
+/// CGRAM (`palette` must be below 64, 16 or 1 respectively).
+pub fn render_tiles<FB: FrameBuffer>(
+    ppu: &Ppu<FB>,
+    addr: u16,
+    count: u16,
+    bits: u8,
+    palette: u8,
+    columns: u16,
+) -> Image {
+    assert!(
+        matches!(bits, 2 | 4 | 8),
+        "unsupported tile depth of {bits} bits per pixel"
+    );
+    let palette_base = u16::from(palette) << bits;
+    assert!(
+        palette_base < 0x100,
+        "palette {palette} is out of range for {bits} bits per pixel"
+    );
+    let palette_base = palette_base as u8;
+    let columns = columns.max(1);
+    let rows = count.div_ceil(columns);
+    let mut image = Image::new(u32::from(columns) << 3, u32::from(rows) << 3);
+    for nr in 0..count {
+        let tile = decode_tile(
+            ppu.vram(),
+            addr.wrapping_add(nr.wrapping_mul(tile_words(bits))),
+            bits,
+        );
+        let [tx, ty] = [nr % columns, nr / columns].map(|v| u32::from(v) << 3);
+        for (y, row) in tile.iter().enumerate() {
+            for (x, &idx) in row.iter().enumerate() {
+                let color = palette_color(ppu.cgram(), palette_base, idx);
+                image.set(tx + x as u32, ty + y as u32, color);
+            }
+        }
+    }
+    image
+}
+
+/// Render the whole tilemap of background `nr` in the current BG mode.
+///
+/// Scrolling, windows and priorities are ignored. Returns `None`, if the
+/// background is not available in the current mode or the mode is Mode 7
+/// (see [`render_mode7`]).
+pub fn render_tilemap<FB: FrameBuffer>(ppu: &Ppu<FB>, nr: u8) -> Option<Image> {
+    let bits = ppu.bg_bits(nr).filter(|_| ppu.bg_mode() != 7)?;
+    let bg = ppu.bg(nr);
+    let [tw, th] = bg.tile_size().map(u16::from);
+    let [mw, mh] = bg.size().map(u16::from);
+    let mut image = Image::new(u32::from(mw * tw), u32::from(mh * th));
+    for tile_y in 0..mh {
+        for tile_x in 0..mw {
+            let entry = ppu.read_bg_map(nr, tile_x, tile_y);
+            let (char_nr, palette_nr, xflip, yflip) = (
+                entry & 0x3ff,
+                ((entry >> 10) & 7) as u8,
+                entry & 0x4000 > 0,
+                entry & 0x8000 > 0,
+            );
+            let palette_base = if ppu.bg_mode() == 0 {
+                (palette_nr << 2) | (nr << 5)
+            } else if bits == 8 {
+                0
+            } else {
+                palette_nr << bits
+            };
+            // 16x16 tiles consist of four 8x8 characters
+            for char_y in 0..th >> 3 {
+                for char_x in 0..tw >> 3 {
+                    let char_nr = char_nr + char_x + (char_y << 4);
+                    let addr = bg
+                        .tile_base_addr()
+                        .wrapping_add(char_nr.wrapping_mul(tile_words(bits)));
+                    let tile = decode_tile(ppu.vram(), addr, bits);
+                    for (py, row) in tile.iter().enumerate() {
+                        for (px, &idx) in row.iter().enumerate() {
+                            let (sx, sy) = ((char_x << 3) + px as u16, (char_y << 3) + py as u16);
+                            let (x, y) = (
+                                if xflip { tw - 1 - sx } else { sx },
+                                if yflip { th - 1 - sy } else { sy },
+                            );
+                            let color = palette_color(ppu.cgram(), palette_base, idx);
+                            image.set(
+                                u32::from(tile_x * tw + x),
+                                u32::from(tile_y * th + y),
+                                color,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Some(image)
+}
+
+/// Render the 1024x1024 pixels of the Mode 7 plane without any transformation
+pub fn render_mode7<FB: FrameBuffer>(ppu: &Ppu<FB>) -> Image {
+    let mut image = Image::new(1024, 1024);
+    for y in 0..1024u16 {
+        for x in 0..1024u16 {
+            let char_nr = ppu.vram().read((x >> 3) | ((y >> 3) << 7)).to_le_bytes()[0];
+            let pixel_addr = (u16::from(char_nr) << 6) | (x & 7) | ((y & 7) << 3);
+            let idx = ppu.vram().read(pixel_addr).to_le_bytes()[1];
+            image.set(x.into(), y.into(), palette_color(ppu.cgram(), 0, idx));
+        }
+    }
+    image
+}
+
+/// A decoded OAM entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sprite {
+    /// Index in OAM (0-127)
+    pub index: u8,
+    /// Horizontal position in the range -256..256
+    pub x: i16,
+    pub y: u8,
+    /// Width and height in pixels
+    pub size: [u8; 2],
+    pub is_large: bool,
+    pub tile_nr: u8,
+    /// VRAM word address of the first tile
+    pub tile_addr: u16,
+    pub priority: u8,
+    /// Sprite palette number (0-7), the CGRAM address is `0x80 + palette * 16`
+    pub palette: u8,
+    pub xflip: bool,
+    pub yflip: bool,
+}
+
+impl Sprite {
+    fn decode<FB: FrameBuffer>(ppu: &Ppu<FB>, index: u8, obj: &Object) -> Self {
+        Self {
+            index,
+            x: obj.x,
+            y: obj.y,
+            size: ppu.obj_size(obj.is_large),
+            is_large: obj.is_large,
+            tile_nr: obj.tile_nr,
+            tile_addr: obj.get_tile_addr(ppu.obj_tile_addr(obj.attrs & 1 > 0), 0, 0),
+            priority: obj.get_priority(),
+            palette: obj.get_palette_nr(),
+            xflip: obj.is_xflip(),
+            yflip: obj.is_yflip(),
+        }
+    }
+}
+
+/// Get all 128 sprites in OAM order
+pub fn sprites<FB: FrameBuffer>(ppu: &Ppu<FB>) -> Vec<Sprite> {
+    ppu.oam()
+        .objects()
+        .iter()
+        .enumerate()
+        .map(|(i, obj)| Sprite::decode(ppu, i as u8, obj))
+        .collect()
+}
+
+/// Render a single sprite as it appears on the screen (including flips)
+pub fn render_sprite<FB: FrameBuffer>(ppu: &Ppu<FB>, index: u8) -> Image {
+    let obj = &ppu.oam().objects()[usize::from(index & 0x7f)];
+    let sprite = Sprite::decode(ppu, index, obj);
+    let [w, h] = sprite.size;
+    let base = ppu.obj_tile_addr(obj.attrs & 1 > 0);
+    let palette_base = 0x80 | (sprite.palette << 4);
+    let mut image = Image::new(w.into(), h.into());
+    for char_y in 0..h >> 3 {
+        for char_x in 0..w >> 3 {
+            let tile = decode_tile(ppu.vram(), obj.get_tile_addr(base, char_x, char_y), 4);
+            for (py, row) in tile.iter().enumerate() {
+                for (px, &idx) in row.iter().enumerate() {
+                    let (sx, sy) = ((char_x << 3) + px as u8, (char_y << 3) + py as u8);
+                    let x = if sprite.xflip { w - 1 - sx } else { sx };
+                    let y = if sprite.yflip { h - 1 - sy } else { sy };
+                    let color = palette_color(ppu.cgram(), palette_base, idx);
+                    image.set(x.into(), y.into(), color);
+                }
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ArrayFrameBuffer;
+
+    /// Create a PPU with `words` written to the start of VRAM and
+    /// CGRAM filled with colors, whose red component is the low 5 bits of their address
+    fn ppu_with_vram(words: &[u16]) -> Ppu<ArrayFrameBuffer> {
+        let mut ppu = Ppu::new(ArrayFrameBuffer::new(), false);
+        // VMAIN: increment after writing the high byte
+        ppu.write_register(0x15, 0x80);
+        ppu.write_register(0x16, 0);
+        ppu.write_register(0x17, 0);
+        for word in words {
+            let [low, high] = word.to_le_bytes();
+            ppu.write_register(0x18, low);
+            ppu.write_register(0x19, high);
+        }
+        ppu.write_register(0x21, 0);
+        for addr in 0..=0xffu8 {
+            ppu.write_register(0x22, addr & 0x1f);
+            ppu.write_register(0x22, 0);
+        }
+        ppu
+    }
+
+    /// Bitplanes of a tile, whose rows contain the palette indices
+    /// `[3, 2, 1, 0, 3, 2, 1, 0]` (planes 0/1), `+ [4, 4, 4, 4, 0, 0, 0, 0]`
+    /// (planes 2/3) and `+ 32` (plane 5)
+    fn test_tile() -> Vec<u16> {
+        [0xccaa, 0x00f0, 0xff00, 0x0000]
+            .into_iter()
+            .flat_map(|word| [word; 8])
+            .collect()
+    }
+
+    #[test]
+    fn decode_tile_depths() {
+        let ppu = ppu_with_vram(&test_tile());
+        for (bits, row) in [
+            (2, [3, 2, 1, 0, 3, 2, 1, 0]),
+            (4, [7, 6, 5, 4, 3, 2, 1, 0]),
+            (8, [39, 38, 37, 36, 35, 34, 33, 32]),
+        ] {
+            assert_eq!(decode_tile(ppu.vram(), 0, bits), [row; 8], "{bits} bpp");
+        }
+    }
+
+    #[test]
+    fn render_tiles_with_palette() {
+        let ppu = ppu_with_vram(&test_tile());
+        let image = render_tiles(&ppu, 0, 3, 2, 1, 2);
+        assert_eq!([image.width, image.height], [16, 16]);
+        let color = |addr| cgram_color(ppu.cgram(), addr);
+        let rows = [
+            // first tile (planes 0/1): indices 3, 2, 1, 0 of palette 1
+            (0, 0, [color(7), color(6), color(5), [0; 4]]),
+            // second tile (planes 2/3): indices 1, 1, 1, 1, 0
+            (8, 0, [color(5); 4]),
+            (12, 0, [[0; 4]; 4]),
+            // third tile (planes 4/5): index 2
+            (0, 8, [color(6); 4]),
+        ];
+        for (x, y, pixels) in rows {
+            for (i, pixel) in pixels.into_iter().enumerate() {
+                assert_eq!(image.get(x + i as u32, y), pixel, "({}, {y})", x + i as u32);
+            }
+        }
+        // the fourth tile is missing in the last row
+        assert_eq!(image.get(8, 8), [0; 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn render_tiles_rejects_large_palette() {
+        let ppu = ppu_with_vram(&[]);
+        render_tiles(&ppu, 0, 1, 4, 16, 1);
+    }
+}
@@ -1,14 +1,19 @@
 pub mod backend;
 pub mod cartridge;
+pub mod color;
 pub mod controller;
 pub mod cpu;
 pub mod device;
 pub mod dma;
 pub mod enhancement;
+pub mod inspect;
 mod instr;
 pub mod oam;
 pub mod ppu;
 mod registers;
+pub mod resample;
 pub mod smp;
 pub mod spc700;
+pub mod spc_file;
 mod timing;
+pub mod wav;
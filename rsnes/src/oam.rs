@@ -113,6 +113,10 @@ impl Oam {
         self.priority = value & 0x80 > 0;
     }
 
+    pub fn objects(&self) -> &[Object; 128] {
+        &self.objs
+    }
+
     pub fn get_first_sprite(&self) -> u8 {
         if self.priority {
             ((self.addr_inc >> 1) & 0x7f) as u8
@@ -203,6 +207,10 @@ impl CgRam {
         val
     }
 
+    pub const fn data(&self) -> &[u8; 512] {
+        &self.data
+    }
+
     pub const fn main_screen_backdrop(&self) -> u16 {
         u16::from_le_bytes([self.data[0], self.data[1]])
     }
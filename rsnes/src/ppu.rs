@@ -1,12 +1,18 @@
-use crate::oam::{CgRam, Oam, Object};
+use crate::{
+    backend::PixelFormat,
+    oam::{CgRam, Oam, Object},
+};
 use core::mem::{replace, take};
 use save_state::{SaveStateDeserializer, SaveStateSerializer};
 use save_state_macro::*;
 
 pub const VRAM_SIZE: usize = 0x8000;
 pub const SCREEN_WIDTH: u32 = 256;
+pub const MAX_SCREEN_WIDTH: u32 = 512;
 pub const MAX_SCREEN_HEIGHT: u32 = 224;
 pub const MAX_SCREEN_HEIGHT_OVERSCAN: u32 = 239;
+/// The maximum internal resolution multiplier of HD Mode 7
+pub const MAX_MODE7_SCALE: u8 = 4;
 pub const CHIP_5C77_VERSION: u8 = 1;
 pub const CHIP_5C78_VERSION: u8 = 3;
 
@@ -16,6 +22,9 @@ pub const CHIP_5C78_VERSION: u8 = 3;
 // there is garbage for about 16-24 pixels.
 pub const RAY_AHEAD_CYCLES: u16 = 20 * 4;
 
+/// The H counter position, at which the first visible pixel is output
+pub const FIRST_VISIBLE_DOT: u16 = 22;
+
 static OBJ_SIZES: [[[u8; 2]; 2]; 8] = [
     [[8, 8], [16, 16]],
     [[8, 8], [32, 32]],
@@ -129,6 +138,16 @@ impl Color {
         [self.r, self.g, self.b, 255]
     }
 
+    /// Get the 5-bit red, green and blue components
+    pub const fn to_rgb5(self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    /// Get the color as a 15-bit BGR value like in CGRAM
+    pub const fn to_bgr555(self) -> u16 {
+        (self.r & 0x1f) as u16 | (((self.g & 0x1f) as u16) << 5) | (((self.b & 0x1f) as u16) << 10)
+    }
+
     pub fn to_rgba8_with_brightness(self, brightness: u8) -> [u8; 4] {
         if brightness == 0 {
             [0; 4]
@@ -259,6 +278,46 @@ impl Layer {
     }
 }
 
+/// Rendering features, which can be force-disabled independent of the
+/// TM/TS and window registers, e.g. to find the layer causing a graphics glitch.
+///
+/// A value of `true` means that the feature is drawn as selected by the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerMask {
+    /// BG1 to BG4
+    pub bgs: [bool; 4],
+    /// Sprites with priority 0 to 3
+    pub objs: [bool; 4],
+    pub main_screen: bool,
+    pub sub_screen: bool,
+    pub color_math: bool,
+    pub windows: bool,
+}
+
+impl LayerMask {
+    pub const ALL: Self = Self {
+        bgs: [true; 4],
+        objs: [true; 4],
+        main_screen: true,
+        sub_screen: true,
+        color_math: true,
+        windows: true,
+    };
+
+    fn shows(&self, layer: &DrawLayer) -> bool {
+        match *layer {
+            DrawLayer::Bg { nr, .. } => self.bgs[usize::from(nr)],
+            DrawLayer::Sprite { prio } => self.objs[usize::from(prio)],
+        }
+    }
+}
+
+impl Default for LayerMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MaskLogic {
@@ -403,11 +462,23 @@ impl Mode7Settings {
     }
 }
 
+/// The Mode 7 transformation of a scanline,
+/// used to interpolate between scanlines in HD Mode 7
+#[derive(Debug, Clone, Copy, Default, InSaveState)]
+struct Mode7Line {
+    /// The position of the first pixel (see `Mode7Settings::tmp4`)
+    origin: [i32; 2],
+    /// The matrix parameters A and C
+    step: [i32; 2],
+}
+
 #[derive(Debug, Clone, Copy, Default, InSaveState)]
 struct CachedTile {
     tile: u64,
     palette_nr: u8,
     x: u8,
+    /// differs within a scanline only with offset-per-tile
+    y: u16,
     prio: bool,
 }
 
@@ -455,6 +526,30 @@ impl Bg {
             cached_tile: None,
         }
     }
+
+    /// Size of a single tile in pixels (8 or 16)
+    pub const fn tile_size(&self) -> [u8; 2] {
+        self.tile_size
+    }
+
+    /// Size of the tilemap in tiles (32 or 64)
+    pub const fn size(&self) -> [u8; 2] {
+        self.size
+    }
+
+    /// VRAM word address of the tilemap
+    pub const fn map_base_addr(&self) -> u16 {
+        self.map_base_addr
+    }
+
+    /// VRAM word address of the character data
+    pub const fn tile_base_addr(&self) -> u16 {
+        self.tile_base_addr
+    }
+
+    pub const fn scroll(&self) -> [u16; 2] {
+        self.scroll
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -621,6 +716,18 @@ impl BgMode {
     }
 }
 
+/// Selects when the PPU draws the pixels of a scanline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Draw each scanline at once, shortly before the beam reaches its end.
+    /// This is fast, but register writes in the middle of a scanline
+    /// affect the whole line.
+    Scanline,
+    /// Draw the pixels as the beam advances, so that register writes in the
+    /// middle of a scanline (e.g. timed with H-IRQs) only affect the following pixels
+    Dot,
+}
+
 #[derive(Debug, Clone, InSaveState)]
 pub struct Ppu<FB: crate::backend::FrameBuffer> {
     #[except((|_v, _s| ()), (|_v, _s| ()))]
@@ -650,11 +757,49 @@ pub struct Ppu<FB: crate::backend::FrameBuffer> {
     window_positions: [[u8; 2]; 2],
     overscan: bool,
     pseudo512: bool,
+    /// Synchronize to an external video signal and show it underneath
+    /// the picture (super-impose).
+    ///
+    /// Only the compositing is emulated. The timing effects are out of scope:
+    /// the H/V counters do not follow the external signal and it never
+    /// latches them, the console always keeps its own timing.
+    external_sync: bool,
+    /// The current frame contains at least one hi-res line
+    hires_frame: bool,
+    /// The current frame is drawn interlaced
+    interlace_frame: bool,
+    /// Internal resolution multiplier of Mode 7 (HD Mode 7), 1 disables it
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    mode7_scale: u8,
+    /// Interpolate the Mode 7 transformation between scanlines in HD Mode 7
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    mode7_perspective: bool,
+    /// Frame buffer pixels per screen pixel in both directions in the current frame
+    frame_scale: u8,
+    /// The current frame contains at least one Mode 7 line
+    mode7_in_frame: bool,
+    /// The current frame contains at least one hi-res line, even if it is scaled
+    hires_in_frame: bool,
+    /// The Mode 7 transformation of the previous and the current scanline
+    mode7_lines: [Option<Mode7Line>; 2],
+    /// The Mode 7 position of the sub-pixel, which is drawn in HD Mode 7
+    mode7_hd_pos: Option<[i32; 2]>,
     mosaic_size: u8,
     mode7_settings: Mode7Settings,
     field: bool,
     force_blank: bool,
     is_pal: bool,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    render_mode: RenderMode,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    layer_mask: LayerMask,
+    /// Draw all sprites of a line, ignoring the 32 sprite and 34 tile limits
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    unlimited_sprites: bool,
+    /// The scanline, which is currently drawn by the dot renderer
+    dot_line: u16,
+    /// The next pixel of `dot_line`, the dot renderer will draw
+    next_dot: u16,
     pub(crate) open_bus1: u8,
     pub(crate) open_bus2: u8,
 }
@@ -686,16 +831,82 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             window_positions: [[0; 2]; 2],
             overscan: false,
             pseudo512: false,
+            external_sync: false,
+            hires_frame: false,
+            interlace_frame: false,
+            mode7_scale: 1,
+            mode7_perspective: true,
+            frame_scale: 1,
+            mode7_in_frame: false,
+            hires_in_frame: false,
+            mode7_lines: [None; 2],
+            mode7_hd_pos: None,
             mosaic_size: 0,
             mode7_settings: Mode7Settings::new(),
             field: false,
             force_blank: true,
             is_pal,
+            render_mode: RenderMode::Scanline,
+            layer_mask: LayerMask::ALL,
+            unlimited_sprites: false,
+            dot_line: 0,
+            next_dot: 0,
             open_bus1: 0,
             open_bus2: 0,
         }
     }
 
+    /// Reset the PPU like a pulse on the /RESET pin does.
+    ///
+    /// The screen gets forced blank and the H/V counters restart.
+    /// All other registers as well as VRAM, CGRAM and OAM keep their values.
+    pub fn reset(&mut self) {
+        self.force_blank = true;
+        self.pos = Default::default();
+        self.latched = Default::default();
+        self.overflow_flags = 0;
+        self.field = false;
+        self.dot_line = 0;
+        self.next_dot = 0;
+    }
+
+    /// Reinitialize all registers and clear VRAM, CGRAM and OAM
+    pub fn power_cycle(&mut self) {
+        let bg_mode = BgMode::new(0, false, false);
+        self.oam = Oam::new();
+        self.cgram = CgRam::new();
+        self.vram = Vram::new();
+        self.bgs = [Bg::new(); 4];
+        self.bg_mode = bg_mode;
+        self.bg3_prio = false;
+        self.brightness = 15;
+        self.draw_layers = Layers::from_bgmode(bg_mode);
+        self.obj_size = OBJ_SIZES[0];
+        self.obj_tile_addr = [0; 2];
+        self.obj_layer = Layer::new();
+        self.obj_cache = [ObjCacheEntry::EMPTY; 256];
+        self.color_math = ColorMath::new();
+        self.direct_color_mode = false;
+        self.object_interlace = false;
+        self.interlace_active = false;
+        self.window_positions = [[0; 2]; 2];
+        self.overscan = false;
+        self.pseudo512 = false;
+        self.external_sync = false;
+        self.hires_frame = false;
+        self.interlace_frame = false;
+        self.frame_scale = 1;
+        self.mode7_in_frame = false;
+        self.hires_in_frame = false;
+        self.mode7_lines = [None; 2];
+        self.mode7_hd_pos = None;
+        self.mosaic_size = 0;
+        self.mode7_settings = Mode7Settings::new();
+        self.open_bus1 = 0;
+        self.open_bus2 = 0;
+        self.reset()
+    }
+
     /// 2134 - 213f
     pub fn read_register(&mut self, addr: u8) -> Option<u8> {
         assert!(addr >= 0x34 && addr <= 0x3f);
@@ -730,7 +941,8 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
                 // STAT78
                 self.latched.flip = [false; 2];
                 Some(
-                    ((take(&mut self.latched.latched) as u8) << 6)
+                    ((self.field as u8) << 7)
+                        | ((take(&mut self.latched.latched) as u8) << 6)
                         | (self.open_bus2 & 0x20)
                         | CHIP_5C78_VERSION
                         | ((self.is_pal as u8) << 4),
@@ -968,9 +1180,9 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
                 self.pseudo512 = val & 8 > 0;
                 self.bg_mode.extbg = val & 0x40 > 0;
                 self.draw_layers = Layers::from_bgmode(self.bg_mode);
-                if val & 0x80 > 0 {
-                    todo!("what the hack is super imposing!?")
-                }
+                // The external picture is provided by the frame buffer,
+                // the timing effects of external sync are not emulated
+                self.external_sync = val & 0x80 > 0;
             }
             _ => unreachable!(),
         }
@@ -1034,13 +1246,14 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
     }
 
     fn fetch_bg7_tile(&mut self, x: u8, nr: u8, prio: bool) -> Option<Color> {
-        let x = if self.mode7_settings.x_mirror { !x } else { x };
-
-        let v = [
-            (self.mode7_settings.tmp4[0], self.mode7_settings.params[0]),
-            (self.mode7_settings.tmp4[1], self.mode7_settings.params[2]),
-        ]
-        .map(|(c, p)| c.wrapping_add(p as i16 as i32 * i32::from(x)));
+        let v = self.mode7_hd_pos.unwrap_or_else(|| {
+            let x = if self.mode7_settings.x_mirror { !x } else { x };
+            [
+                (self.mode7_settings.tmp4[0], self.mode7_settings.params[0]),
+                (self.mode7_settings.tmp4[1], self.mode7_settings.params[2]),
+            ]
+            .map(|(c, p)| c.wrapping_add(p as i16 as i32 * i32::from(x)))
+        });
 
         let v = v.map(|c| (((c as u32) >> 8) & 0xffff) as u16);
         let char_nr = if self.mode7_settings.wrap || !v.iter().any(|&c| c > 0x3ff) {
@@ -1072,38 +1285,103 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
         }
     }
 
-    pub fn fetch_bg_tile(&mut self, x: u8, y: u16, nr: u8, bits: u8, prio: bool) -> Option<Color> {
+    /// Read the tilemap entry of a background at the tile position
+    pub fn read_bg_map(&self, nr: u8, tile_x: u16, tile_y: u16) -> u16 {
+        let bg = &self.bgs[usize::from(nr)];
+        let (tile_x, tile_y) = (tile_x & 0x3f, tile_y & 0x3f);
+        let map_nr = match bg.size {
+            [64, 32] => (tile_x << 5) & 0x400,
+            [32, 64] => (tile_y << 5) & 0x400,
+            [64, 64] => ((tile_x << 5) | ((tile_y & 0x20) << 6)) & 0xc00,
+            _ => 0,
+        };
+        let map_addr = bg
+            .map_base_addr
+            .wrapping_add((tile_x & 0x1f) | ((tile_y & 0x1f) << 5))
+            .wrapping_add(map_nr);
+        self.vram.read(map_addr)
+    }
+
+    /// Modes 2, 4 and 6 use the tilemap of BG3 as a table of
+    /// column-wise scroll offsets for BG1 and BG2
+    fn has_offset_per_tile(&self) -> bool {
+        matches!(self.bg_mode.num, 2 | 4 | 6)
+    }
+
+    /// Get the scroll values of BG1 or BG2 for the tile column at `x`
+    /// from the offset-per-tile table.
+    ///
+    /// Bit 13 (BG1) or bit 14 (BG2) of a table entry enables the offset.
+    /// Mode 4 only has a single table row, where bit 15 selects between
+    /// horizontal and vertical offsets.
+    fn offset_per_tile_scroll(&self, nr: u8, x: u16) -> [u16; 2] {
+        let mut scroll = self.bgs[usize::from(nr)].scroll;
+        let hires = u8::from(self.is_hires_mode());
+        let column_width = 8 << hires;
+        let x = x + ((scroll[0] << hires) & (column_width - 1));
+        // the leftmost column is never affected
+        if x < column_width {
+            return scroll;
+        }
+        let bg3 = &self.bgs[2];
+        let tile_x = (x >> (3 + hires)) - 1 + ((bg3.scroll[0] & 0x3ff) >> 3);
+        let tile_y = (bg3.scroll[1] & 0x3ff) >> 3;
+        let enable = 0x2000 << nr;
+        let mut apply = |entry: u16, vertical: bool| {
+            if entry & enable > 0 {
+                if vertical {
+                    scroll[1] = entry & 0x3ff
+                } else {
+                    scroll[0] = (entry & 0x3f8) | (scroll[0] & 7)
+                }
+            }
+        };
+        let entry = self.read_bg_map(2, tile_x, tile_y);
+        if self.bg_mode.num == 4 {
+            apply(entry, entry & 0x8000 > 0);
+        } else {
+            apply(entry, false);
+            apply(self.read_bg_map(2, tile_x, tile_y + 1), true);
+        }
+        scroll
+    }
+
+    /// Fetch the color of a background pixel.
+    ///
+    /// In the hi-res modes 5 and 6 `x` is in the range `0..512`.
+    pub fn fetch_bg_tile(&mut self, x: u16, y: u16, nr: u8, bits: u8, prio: bool) -> Option<Color> {
         if self.bg_mode.num == 7 {
-            return self.fetch_bg7_tile(x, nr, prio);
+            return self.fetch_bg7_tile(x as u8, nr, prio);
         }
-        // TODO: implement offset-per-tile
+        let hires = u8::from(self.is_hires_mode());
+        let y = if hires > 0 && self.interlace_active {
+            (y << 1) | u16::from(self.field)
+        } else {
+            y
+        };
+        let scroll = if nr < 2 && self.has_offset_per_tile() {
+            self.offset_per_tile_scroll(nr, x)
+        } else {
+            self.bgs[usize::from(nr)].scroll
+        };
         let bg = &self.bgs[usize::from(nr)];
-        let x = (x as i16 + (((bg.scroll[0] << 6) as i16) >> 6)) as u16 & 0x3ff;
-        let y = (y as i16 + (((bg.scroll[1] << 6) as i16) >> 6)) as u16 & 0x3ff;
+        let scroll_x = (((scroll[0] << 6) as i16) >> 6) << hires;
+        let x = (x as i16).wrapping_add(scroll_x) as u16 & (0x7ff >> (1 - hires));
+        let y = (y as i16 + (((scroll[1] << 6) as i16) >> 6)) as u16 & 0x3ff;
         let (x, y) = if let Some(start) = bg.mosaic_start {
             let sz = self.mosaic_size as u16;
-            let ys = y - start;
-            (x - (x % sz), (ys - (ys % sz)) + start)
+            let (szx, ys) = (sz << hires, y - start);
+            (x - (x % szx), (ys - (ys % sz)) + start)
         } else {
             (x, y)
         };
         let cache_x = (x >> 3) as u8;
-        let tile = if let Some(tile) = bg.cached_tile.filter(|t| t.x == cache_x) {
+        let tile = if let Some(tile) = bg.cached_tile.filter(|t| t.x == cache_x && t.y == y) {
             tile
         } else {
-            let tile_x = (x >> bg.tile_size[0].trailing_zeros()) & 0x3f;
-            let tile_y = (y >> bg.tile_size[1].trailing_zeros()) & 0x3f;
-            let map_nr = match bg.size {
-                [64, 32] => (tile_x << 5) & 0x400,
-                [32, 64] => (tile_y << 5) & 0x400,
-                [64, 64] => ((tile_x << 5) | ((tile_y & 0x20) << 6)) & 0xc00,
-                _ => 0,
-            };
-            let map_addr = bg
-                .map_base_addr
-                .wrapping_add((tile_x & 0x1f) | ((tile_y & 0x1f) << 5))
-                .wrapping_add(map_nr);
-            let map_val = self.vram.read(map_addr);
+            let tile_x = x >> bg.tile_size[0].trailing_zeros();
+            let tile_y = y >> bg.tile_size[1].trailing_zeros();
+            let map_val = self.read_bg_map(nr, tile_x, tile_y);
             let (char_nr, palette_nr, sel_prio, xflip, yflip) = (
                 map_val & 0x3ff,
                 ((map_val >> 10) & 7) as u8,
@@ -1121,6 +1399,7 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             let tile = self.fetch_tile(x, y, base, tw, th, char_nr, xflip, bits);
             let tile = CachedTile {
                 x: cache_x,
+                y,
                 prio: sel_prio,
                 tile,
                 palette_nr,
@@ -1157,6 +1436,7 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
     pub fn fetch_screen(
         &mut self,
         x: u8,
+        bg_x: u16,
         y: u16,
         mainscreen: bool,
         subscreen: bool,
@@ -1166,14 +1446,19 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
         let mut layer_color_math = None;
         for draw_ly_idx in 0..self.draw_layers.size {
             let draw_ly = &self.draw_layers.arr[usize::from(draw_ly_idx)];
+            if !self.layer_mask.shows(draw_ly) {
+                continue;
+            }
             let ly = self.get_layer_from_draw_layer(&draw_ly);
             let in_window = self.is_in_window(x, &ly.window);
             let [is_main, is_sub] = [
                 ly.main_screen
+                    && self.layer_mask.main_screen
                     && !main_found
                     && mainscreen
                     && (!ly.window_area_main_screen || !in_window),
                 ly.sub_screen
+                    && self.layer_mask.sub_screen
                     && !sub_found
                     && subscreen
                     && (!ly.window_area_sub_screen || !in_window),
@@ -1183,7 +1468,7 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             }
             let mut layer_color_math_ = ly.color_math;
             if let Some(color) = match draw_ly {
-                &DrawLayer::Bg { nr, bits, prio } => self.fetch_bg_tile(x, y, nr, bits, prio),
+                &DrawLayer::Bg { nr, bits, prio } => self.fetch_bg_tile(bg_x, y, nr, bits, prio),
                 &DrawLayer::Sprite { prio } => {
                     let entry = self.obj_cache[usize::from(x)];
                     if prio == entry.prio && entry.palette_addr != 0 {
@@ -1212,7 +1497,14 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             }
         }
         if !main_found && mainscreen {
-            main = self.cgram.main_screen_backdrop().into()
+            let external = if self.external_sync {
+                self.frame_buffer.external_pixel(x, y - 1)
+            } else {
+                None
+            };
+            main = external
+                .unwrap_or_else(|| self.cgram.main_screen_backdrop())
+                .into()
         }
         (
             main,
@@ -1221,7 +1513,8 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
         )
     }
 
-    pub fn draw_pixel(&mut self, x: u8, y: u16) -> [u8; 4] {
+    /// Get if the main screen and color math are enabled at `x` (see CGWSEL)
+    fn color_math_enables(&self, x: u8) -> [bool; 2] {
         let mut lazy_in_window = None;
         let mut in_window = || {
             if let Some(iw) = lazy_in_window {
@@ -1240,27 +1533,71 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             0 | 3 => i == 0,
             _ => (i == 2) ^ in_window(),
         });
+        [main_enable, color_enable && self.layer_mask.color_math]
+    }
+
+    fn apply_color_math(&self, main: Color, sub: Option<Color>, main_enable: bool) -> Color {
+        let sub_or_backdrop = sub.unwrap_or(self.color_math.color);
+        let mut color = if self.color_math.subtract_color {
+            main - sub_or_backdrop
+        } else {
+            main + sub_or_backdrop
+        };
+        if self.color_math.half_color && main_enable && sub.is_some() {
+            color = color.half();
+        }
+        color.map(|c| c.clamp(0, 0x1f))
+    }
+
+    /// Convert a color to the pixel format of the frame buffer
+    fn output_color(&self, color: Color) -> [u8; 4] {
+        match self.frame_buffer.pixel_format() {
+            PixelFormat::Rgba8 => color.to_rgba8_with_brightness(self.brightness),
+            PixelFormat::Bgr555 => {
+                let [low, high] = color.to_bgr555().to_le_bytes();
+                [low, high, self.brightness, 0]
+            }
+        }
+    }
+
+    pub fn draw_pixel(&mut self, x: u8, y: u16) -> [u8; 4] {
+        let [main_enable, color_enable] = self.color_math_enables(x);
         let (main, sub, color_math) = self.fetch_screen(
             x,
+            x.into(),
             y,
             main_enable,
             color_enable && self.color_math.add_subscreen,
         );
         let color = if color_math && color_enable {
-            let sub_or_backdrop = sub.unwrap_or(self.color_math.color);
-            let mut color = if self.color_math.subtract_color {
-                main - sub_or_backdrop
-            } else {
-                main + sub_or_backdrop
-            };
-            if self.color_math.half_color && main_enable && sub.is_some() {
-                color = color.half();
-            }
-            color.map(|c| c.clamp(0, 0x1f))
+            self.apply_color_math(main, sub, main_enable)
         } else {
             main
         };
-        color.to_rgba8_with_brightness(self.brightness)
+        self.output_color(color)
+    }
+
+    /// Draw both halves of a hi-res pixel.
+    ///
+    /// The left half shows the sub screen and the right half shows the main screen.
+    /// The backgrounds of mode 5 and 6 are fetched at the doubled horizontal resolution.
+    pub fn draw_hires_pixel(&mut self, x: u8, y: u16) -> [[u8; 4]; 2] {
+        let [main_enable, color_enable] = self.color_math_enables(x);
+        let [sub_x, main_x] = if self.is_hires_mode() {
+            let bg_x = u16::from(x) << 1;
+            [bg_x, bg_x | 1]
+        } else {
+            [x.into(); 2]
+        };
+        let (main, _, color_math) = self.fetch_screen(x, main_x, y, main_enable, false);
+        let (_, sub, _) = self.fetch_screen(x, sub_x, y, false, true);
+        let halves = if color_math && color_enable {
+            let sub = sub.filter(|_| self.color_math.add_subscreen);
+            [self.apply_color_math(main, sub, main_enable); 2]
+        } else {
+            [sub.unwrap_or(self.color_math.color), main]
+        };
+        halves.map(|color| self.output_color(color))
     }
 
     fn draw_obj_8x8_tile(&mut self, obj: &Object, row: u8, tile_x: u8, tile_y: u8, size: [u8; 2]) {
@@ -1295,52 +1632,142 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
     fn refill_obj_cache(&mut self, y: u16) {
         self.obj_cache.fill(ObjCacheEntry::EMPTY);
         let y = (y & 0xff) as u8;
+        let obj_interlace = u8::from(self.object_interlace);
         let mut objs_in_line = 0;
         let mut tiles_in_line = 0;
         let firstsprite = self.oam.get_first_sprite();
+        // The range and time over flags are determined by the first 32 sprites
+        // in range, even if the sprite limit is disabled
+        let visible_tiles = |obj: &Object, width: u8| {
+            (0..width >> 3)
+                .map(|tile_id| obj.x + i16::from(tile_id << 3))
+                .filter(|left| (-7..256).contains(left))
+                .count()
+        };
         for obj_id in 0..128 {
             let obj = &mut self.oam.objs[usize::from(obj_id + firstsprite) & 0x7f];
             obj.used = false;
             let size = self.obj_size[usize::from(obj.is_large)];
             if (-i16::from(size[0]) >= obj.x && obj.x != -256)
                 || obj.x >= 256
-                || y.wrapping_sub(obj.y) >= size[1]
+                || y.wrapping_sub(obj.y) >= size[1] >> obj_interlace
             {
                 continue;
             }
             if objs_in_line >= 32 {
                 self.overflow_flags |= 0x40;
-                break;
+                if !self.unlimited_sprites {
+                    break;
+                }
+            } else {
+                objs_in_line += 1;
+                tiles_in_line += visible_tiles(obj, size[0]);
             }
             obj.used = true;
         }
+        if tiles_in_line > 34 {
+            self.overflow_flags |= 0x80;
+        }
+        let mut tiles_drawn = 0;
         'obj_loop: for obj in 0..128 {
             let obj = self.oam.objs[!usize::from(obj + firstsprite) & 0x7f];
             if !obj.used {
                 continue;
             }
             let size = self.obj_size[usize::from(obj.is_large)];
-            objs_in_line += 1;
             let y = y.wrapping_sub(obj.y);
+            // with OBJ interlace every field draws every other row of the sprites
+            let y = (y << obj_interlace) | (obj_interlace & self.field as u8);
             let y = if obj.is_yflip() { size[1] - y - 1 } else { y };
             'tile_loop: for tile_id in 0..size[0] >> 3 {
                 let left = obj.x + i16::from(tile_id << 3);
                 if left < -7 || left >= 256 {
                     continue 'tile_loop;
                 }
-                if tiles_in_line >= 34 {
-                    self.overflow_flags |= 0x80;
+                if tiles_drawn >= 34 && !self.unlimited_sprites {
                     break 'obj_loop;
                 }
-                tiles_in_line += 1;
+                tiles_drawn += 1;
                 self.draw_obj_8x8_tile(&obj, y, tile_id, y >> 3, size);
             }
         }
     }
 
-    pub fn draw_scanline(&mut self) {
-        let y = self.pos.y + 1;
-        let mut n = usize::from(self.pos.y) * 256;
+    /// Select the output size of the frame, that is about to be drawn
+    fn start_frame(&mut self) {
+        let interlace = self.interlace_active;
+        // HD Mode 7 is used, if the previous frame contained Mode 7 lines
+        let scale =
+            if self.mode7_scale > 1 && !interlace && (self.mode7_in_frame || self.bg_mode.num == 7)
+            {
+                self.mode7_scale
+            } else {
+                1
+            };
+        // hi-res pixels are split in two halves, which requires an even scale
+        let hires = self.hires_in_frame || self.is_hires();
+        let scale = if hires && scale & 1 == 1 {
+            scale - 1
+        } else {
+            scale
+        };
+        self.mode7_in_frame = false;
+        self.hires_in_frame = false;
+        self.mode7_lines = [None; 2];
+        self.frame_scale = scale;
+        if scale > 1 {
+            self.hires_frame = false;
+            self.interlace_frame = false;
+            let scale = u32::from(scale);
+            self.frame_buffer.set_width(SCREEN_WIDTH * scale);
+            self.frame_buffer
+                .set_height(u32::from(self.vend() - 1) * scale);
+            return;
+        }
+        // interlaced frames keep the lines of the previous field,
+        // so they need to stay in the same format
+        if !(interlace && self.interlace_frame) {
+            self.hires_frame = false;
+            self.frame_buffer.set_width(SCREEN_WIDTH);
+        }
+        self.interlace_frame = interlace;
+        self.frame_buffer
+            .set_height(u32::from(self.vend() - 1) << u8::from(interlace));
+    }
+
+    /// Switch the current frame to hi-res output by doubling
+    /// the `drawn` pixels written so far
+    fn expand_to_hires(&mut self, drawn: usize) {
+        self.hires_frame = true;
+        self.frame_buffer.set_width(MAX_SCREEN_WIDTH);
+        let [width, new_width] = [SCREEN_WIDTH, MAX_SCREEN_WIDTH].map(|w| w as usize);
+        let drawn = if self.interlace_frame {
+            MAX_SCREEN_HEIGHT_OVERSCAN as usize * 2 * width
+        } else {
+            drawn
+        };
+        let pixels = self.frame_buffer.mut_pixels();
+        for i in (0..drawn).rev() {
+            let n = (i / width) * new_width + ((i % width) << 1);
+            pixels[n + 1] = pixels[i];
+            pixels[n] = pixels[i];
+        }
+    }
+
+    /// Get the frame buffer row of screen line `y`
+    fn frame_row(&self, y: u16) -> u16 {
+        if self.interlace_frame {
+            ((y - 1) << 1) | u16::from(self.field)
+        } else {
+            y - 1
+        }
+    }
+
+    /// Prepare the per-line state before drawing the first pixel of screen line `y`
+    fn start_scanline(&mut self, y: u16) {
+        if y == 1 {
+            self.start_frame()
+        }
         for bg in &mut self.bgs {
             bg.cached_tile = None;
         }
@@ -1349,9 +1776,7 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
                 bg.mosaic_start = Some(y);
             }
         }
-        if self.force_blank {
-            self.frame_buffer.mut_pixels()[n..n + 256].fill([0; 4])
-        } else {
+        if !self.force_blank {
             self.refill_obj_cache(y - 1);
             self.mode7_settings.tmpy = (y & 0xff) as u8;
             if self.mode7_settings.y_mirror {
@@ -1359,14 +1784,253 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
             }
             self.mode7_settings.update_tmp3::<0>();
             self.mode7_settings.update_tmp3::<1>();
-            for x in 0u8..=255 {
-                self.frame_buffer.mut_pixels()[n] = self.draw_pixel(x, y);
-                n += 1;
+        }
+        let mode7_line = (self.bg_mode.num == 7 && !self.force_blank).then(|| Mode7Line {
+            origin: self.mode7_settings.tmp4,
+            step: [0, 2].map(|i| i32::from(self.mode7_settings.params[i] as i16)),
+        });
+        self.mode7_in_frame |= mode7_line.is_some();
+        self.hires_in_frame |= self.is_hires();
+        self.mode7_lines = [self.mode7_lines[1], mode7_line];
+    }
+
+    /// Get the Mode 7 position of the sub-pixel (`sub_x`, `sub_y`) of pixel `x`
+    /// in HD Mode 7.
+    ///
+    /// The sub-pixel rows lie between the previous and the current scanline,
+    /// the last one matches the current scanline. With perspective correction,
+    /// the transformations of both scanlines are interpolated. Otherwise
+    /// the transformation of the current scanline is extended.
+    fn mode7_hd_pos(&self, x: u8, sub_x: u8, sub_y: u8) -> [i32; 2] {
+        let settings = &self.mode7_settings;
+        let scale = i32::from(self.frame_scale);
+        let sign = |mirror: bool| if mirror { -1 } else { 1 };
+        let x = if settings.x_mirror { !x } else { x };
+        // the position of the sub-pixel in 1/scale pixels
+        let x = i32::from(x) * scale + sign(settings.x_mirror) * i32::from(sub_x);
+        let row = i32::from(sub_y) + 1;
+        let at = |line: &Mode7Line| {
+            [0, 1].map(|i| line.origin[i].wrapping_add(line.step[i].wrapping_mul(x) / scale))
+        };
+        let current = Mode7Line {
+            origin: settings.tmp4,
+            step: [0, 2].map(|i| i32::from(settings.params[i] as i16)),
+        };
+        let pos = at(&current);
+        match self.mode7_lines[0].filter(|_| self.mode7_perspective) {
+            Some(previous) => {
+                let prev = at(&previous);
+                [0, 1].map(|i| {
+                    let delta = i64::from(pos[i].wrapping_sub(prev[i])) * i64::from(row);
+                    prev[i].wrapping_add((delta / i64::from(scale)) as i32)
+                })
+            }
+            None => {
+                let dy = sign(settings.y_mirror) * (row - scale);
+                [0, 1].map(|i| {
+                    let param = i32::from(settings.params[(i << 1) | 1] as i16);
+                    pos[i].wrapping_add(param * dy / scale)
+                })
             }
         }
     }
 
+    /// Draw the pixel `x` of screen line `y` as a block of `frame_scale` times
+    /// `frame_scale` pixels to the frame buffer.
+    ///
+    /// Mode 7 backgrounds are evaluated for every pixel of the block.
+    /// The halves of hi-res pixels are only split evenly with an even scale,
+    /// which gets selected for frames following hi-res lines.
+    fn draw_scaled_dot(&mut self, x: u8, y: u16) {
+        let scale = usize::from(self.frame_scale);
+        let width = SCREEN_WIDTH as usize * scale;
+        let n = usize::from(y - 1) * scale * width + usize::from(x) * scale;
+        let mut block = [[[0; 4]; MAX_MODE7_SCALE as usize]; MAX_MODE7_SCALE as usize];
+        if self.force_blank {
+            // the block stays black
+        } else if self.is_hires() {
+            let halves = self.draw_hires_pixel(x, y);
+            for row in &mut block {
+                row[..scale >> 1].fill(halves[0]);
+                row[scale >> 1..].fill(halves[1]);
+            }
+        } else if self.bg_mode.num == 7 {
+            for (sub_y, row) in block.iter_mut().enumerate().take(scale) {
+                for (sub_x, pixel) in row.iter_mut().enumerate().take(scale) {
+                    self.mode7_hd_pos = Some(self.mode7_hd_pos(x, sub_x as u8, sub_y as u8));
+                    *pixel = self.draw_pixel(x, y);
+                }
+            }
+            self.mode7_hd_pos = None;
+        } else {
+            block = [[self.draw_pixel(x, y); MAX_MODE7_SCALE as usize]; MAX_MODE7_SCALE as usize];
+        }
+        let pixels = self.frame_buffer.mut_pixels();
+        for (i, row) in block.iter().enumerate().take(scale) {
+            let start = n + i * width;
+            pixels[start..start + scale].copy_from_slice(&row[..scale]);
+        }
+    }
+
+    /// Draw the pixel `x` of screen line `y` to the frame buffer
+    fn draw_dot(&mut self, x: u8, y: u16) {
+        if self.frame_scale > 1 {
+            return self.draw_scaled_dot(x, y);
+        }
+        let row = usize::from(self.frame_row(y));
+        let hires = self.is_hires();
+        if hires && !self.hires_frame {
+            self.expand_to_hires(row * SCREEN_WIDTH as usize + usize::from(x))
+        }
+        let n = if self.hires_frame {
+            row * MAX_SCREEN_WIDTH as usize + (usize::from(x) << 1)
+        } else {
+            row * SCREEN_WIDTH as usize + usize::from(x)
+        };
+        if self.force_blank {
+            let len = if self.hires_frame { 2 } else { 1 };
+            self.frame_buffer.mut_pixels()[n..n + len].fill([0; 4])
+        } else if hires {
+            let halves = self.draw_hires_pixel(x, y);
+            self.frame_buffer.mut_pixels()[n..n + 2].copy_from_slice(&halves);
+        } else if self.hires_frame {
+            let pixel = self.draw_pixel(x, y);
+            self.frame_buffer.mut_pixels()[n..n + 2].fill(pixel);
+        } else {
+            self.frame_buffer.mut_pixels()[n] = self.draw_pixel(x, y);
+        }
+    }
+
+    /// Draw the whole upcoming scanline at once
+    pub fn draw_scanline(&mut self) {
+        let y = self.pos.y + 1;
+        self.start_scanline(y);
+        for x in 0u8..=255 {
+            self.draw_dot(x, y)
+        }
+    }
+
+    /// Draw the pixels of the current scanline up to (excluding) pixel `end`.
+    ///
+    /// This is used by [`RenderMode::Dot`] to draw the pixels, the beam
+    /// already passed, before registers change.
+    pub fn draw_dots(&mut self, end: u16) {
+        let y = self.pos.y;
+        if y == 0 || y >= self.vend() {
+            return;
+        }
+        if self.dot_line != y {
+            self.dot_line = y;
+            self.next_dot = 0;
+        }
+        let end = end.min(SCREEN_WIDTH as u16);
+        if self.next_dot >= end {
+            return;
+        }
+        if self.next_dot == 0 {
+            self.start_scanline(y)
+        }
+        for x in self.next_dot..end {
+            self.draw_dot(x as u8, y)
+        }
+        self.next_dot = end;
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode
+    }
+
+    pub fn vram(&self) -> &Vram {
+        &self.vram
+    }
+
+    pub fn cgram(&self) -> &CgRam {
+        &self.cgram
+    }
+
+    pub fn oam(&self) -> &Oam {
+        &self.oam
+    }
+
+    pub fn bg(&self, nr: u8) -> &Bg {
+        &self.bgs[usize::from(nr)]
+    }
+
+    pub fn bg_mode(&self) -> u8 {
+        self.bg_mode.num
+    }
+
+    /// Get the bits per pixel of background `nr` in the current BG mode
+    /// or `None`, if the background is not available in this mode
+    pub fn bg_bits(&self, nr: u8) -> Option<u8> {
+        self.draw_layers.arr[..usize::from(self.draw_layers.size)]
+            .iter()
+            .find_map(|layer| match *layer {
+                DrawLayer::Bg { nr: n, bits, .. } if n == nr => Some(bits),
+                _ => None,
+            })
+    }
+
+    /// Get the size of small or large sprites in pixels
+    pub fn obj_size(&self, is_large: bool) -> [u8; 2] {
+        self.obj_size[usize::from(is_large)]
+    }
+
+    /// Get the VRAM word address of the sprite tiles of the first
+    /// or the second name table (see [`Object::attrs`] bit 0)
+    pub fn obj_tile_addr(&self, second_table: bool) -> u16 {
+        self.obj_tile_addr[usize::from(second_table)]
+    }
+
+    pub fn mode7_scale(&self) -> u8 {
+        self.mode7_scale
+    }
+
+    /// Set the internal resolution multiplier of Mode 7 (HD Mode 7).
+    ///
+    /// Frames following a frame with Mode 7 lines are drawn with `scale`
+    /// times the width and height. All other lines get scaled up.
+    /// A value of 1 disables HD Mode 7, the maximum is [`MAX_MODE7_SCALE`].
+    /// Interlaced frames are never scaled.
+    pub fn set_mode7_scale(&mut self, scale: u8) {
+        self.mode7_scale = scale.clamp(1, MAX_MODE7_SCALE)
+    }
+
+    /// Interpolate the Mode 7 transformation between scanlines in HD Mode 7,
+    /// so that perspective effects done with HDMA look smooth
+    pub fn set_mode7_perspective(&mut self, perspective: bool) {
+        self.mode7_perspective = perspective
+    }
+
+    pub fn has_unlimited_sprites(&self) -> bool {
+        self.unlimited_sprites
+    }
+
+    /// Draw all sprites on a line instead of only the first 32 sprites
+    /// and 34 sprite tiles, which removes sprite flicker in many games.
+    ///
+    /// The range and time over flags in $213E are reported like on the hardware.
+    pub fn set_unlimited_sprites(&mut self, unlimited: bool) {
+        self.unlimited_sprites = unlimited
+    }
+
+    pub fn layer_mask(&self) -> &LayerMask {
+        &self.layer_mask
+    }
+
+    /// Force-disable rendering features (see [`LayerMask`])
+    pub fn layer_mask_mut(&mut self) -> &mut LayerMask {
+        &mut self.layer_mask
+    }
+
     pub fn is_in_window(&self, x: u8, window: &Window) -> bool {
+        if !self.layer_mask.windows {
+            return false;
+        }
         let window_n = |n: usize| {
             (self.window_positions[n][0]..=self.window_positions[n][1]).contains(&x)
                 ^ window.window_inversion[n]
@@ -1404,6 +2068,24 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
         self.latched.latched = true
     }
 
+    /// Backgrounds are drawn at 512 pixels per line in modes 5 and 6
+    fn is_hires_mode(&self) -> bool {
+        matches!(self.bg_mode.num, 5 | 6)
+    }
+
+    /// Get if the current line is output with 512 pixels
+    pub fn is_hires(&self) -> bool {
+        self.pseudo512 || self.is_hires_mode()
+    }
+
+    /// Get if external sync (super-impose) is enabled by bit 7 of SETINI.
+    ///
+    /// This only affects the backdrop (see [`crate::backend::FrameBuffer::external_pixel`]),
+    /// the timing effects of external sync are not emulated.
+    pub fn is_external_sync(&self) -> bool {
+        self.external_sync
+    }
+
     pub fn is_interlaced(&self) -> bool {
         self.interlace_active
     }
@@ -1426,12 +2108,10 @@ impl<FB: crate::backend::FrameBuffer> Ppu<FB> {
         }
     }
 
+    /// Get the number of scanlines of the current frame.
+    /// Interlaced frames with a cleared field bit have an extra scanline.
     pub fn get_scanline_count(&self) -> u16 {
-        if self.is_pal {
-            312
-        } else {
-            262
-        }
+        (if self.is_pal { 312 } else { 262 }) + u16::from(self.is_interlaced() && !self.field)
     }
 
     pub fn is_in_hblank_reg4212(&self) -> bool {
@@ -0,0 +1,275 @@
+//! Conversion of the 32kHz S-DSP output to the sample rate and the
+//! channel layout of an audio device
+//!
+//! # Literature
+//!
+//! - <https://ccrma.stanford.edu/~jos/resample/>
+//! - <https://en.wikipedia.org/wiki/Cubic_Hermite_spline#Catmull%E2%80%93Rom_spline>
+
+use crate::spc700::StereoSample;
+
+/// Number of input samples, the windowed-sinc filter is computed of
+const TAPS: usize = 16;
+/// Number of precomputed fractional positions of the windowed-sinc filter
+const PHASES: usize = 256;
+/// The interpolated position lies between these two input samples of the history
+const CENTER: usize = TAPS / 2 - 1;
+
+/// The interpolation method of a [`Resampler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Fast, but muffles high frequencies and produces aliasing
+    Linear,
+    /// Catmull-Rom spline through four samples
+    Cubic,
+    /// Blackman-windowed sinc filter with 16 taps
+    Sinc,
+}
+
+/// Convert a stream of stereo samples from one sample rate to another
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    interpolation: Interpolation,
+    /// The last `TAPS` input samples, the newest one last
+    history: [[f32; 2]; TAPS],
+    /// Input samples per output sample
+    step: f64,
+    /// Position of the next output sample after `history[CENTER]`
+    position: f64,
+    /// Filter coefficients for `PHASES + 1` fractional positions
+    sinc_table: Vec<[f32; TAPS]>,
+}
+
+fn sinc_table(cutoff: f64) -> Vec<[f32; TAPS]> {
+    use std::f64::consts::PI;
+    (0..=PHASES)
+        .map(|phase| {
+            let frac = phase as f64 / PHASES as f64;
+            let mut coeffs = [0.0; TAPS];
+            for (tap, coeff) in coeffs.iter_mut().enumerate() {
+                let x = tap as f64 - CENTER as f64 - frac;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                let w = PI * x / (TAPS / 2) as f64;
+                let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                *coeff = sinc * window;
+            }
+            // keep the DC gain at exactly 1
+            let sum: f64 = coeffs.iter().sum();
+            coeffs.map(|c| (c / sum) as f32)
+        })
+        .collect()
+}
+
+impl Resampler {
+    /// Panics, if one of the sample rates is 0
+    pub fn new(interpolation: Interpolation, input_rate: u32, output_rate: u32) -> Self {
+        assert!(
+            input_rate > 0 && output_rate > 0,
+            "sample rates must not be 0"
+        );
+        // remove frequencies above the output nyquist frequency when downsampling
+        let cutoff = (f64::from(output_rate) / f64::from(input_rate)).min(1.0) * 0.95;
+        Self {
+            interpolation,
+            history: [[0.0; 2]; TAPS],
+            step: f64::from(input_rate) / f64::from(output_rate),
+            position: 0.0,
+            sinc_table: match interpolation {
+                Interpolation::Sinc => sinc_table(cutoff),
+                Interpolation::Linear | Interpolation::Cubic => Vec::new(),
+            },
+        }
+    }
+
+    pub const fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    /// Get the number of input samples per output sample
+    pub const fn step(&self) -> f64 {
+        self.step
+    }
+
+    /// Change the number of input samples per output sample, e.g. to
+    /// make up for clock differences between the emulation and the device.
+    ///
+    /// Panics, if `step` is not a finite positive number
+    pub fn set_step(&mut self, step: f64) {
+        assert!(
+            step.is_finite() && step > 0.0,
+            "invalid resampler step {}",
+            step
+        );
+        self.step = step
+    }
+
+    fn interpolate(&self, frac: f32) -> [f32; 2] {
+        let h = &self.history;
+        match self.interpolation {
+            Interpolation::Linear => {
+                [0, 1].map(|c| h[CENTER][c] + (h[CENTER + 1][c] - h[CENTER][c]) * frac)
+            }
+            Interpolation::Cubic => [0, 1].map(|ch| {
+                let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|i| h[CENTER - 1 + i][ch]);
+                let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+                let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+                let c = -0.5 * p0 + 0.5 * p2;
+                ((a * frac + b) * frac + c) * frac + p1
+            }),
+            Interpolation::Sinc => {
+                let coeffs = &self.sinc_table[(frac * PHASES as f32).round() as usize];
+                let mut out = [0.0; 2];
+                for (sample, coeff) in h.iter().zip(coeffs) {
+                    out[0] += sample[0] * coeff;
+                    out[1] += sample[1] * coeff;
+                }
+                out
+            }
+        }
+    }
+
+    /// Feed a single input sample and call `output` with every output
+    /// sample (in the range -1.0 to 1.0), that becomes available.
+    pub fn push<F: FnMut([f32; 2])>(&mut self, sample: StereoSample, mut output: F) {
+        self.history.copy_within(1.., 0);
+        self.history[TAPS - 1] = [sample.l, sample.r].map(|v| f32::from(v) / 32768.0);
+        while self.position < 1.0 {
+            output(self.interpolate(self.position as f32));
+            self.position += self.step;
+        }
+        self.position -= 1.0;
+    }
+}
+
+/// Dynamic rate control, which keeps the fill level of an audio buffer
+/// near a target by slightly changing the step of a [`Resampler`].
+///
+/// The emulation and the audio device run on different clocks, so that a
+/// fixed ratio would slowly under- or overrun any buffer in between.
+#[derive(Debug, Clone)]
+pub struct RateControl {
+    nominal_step: f64,
+    target_fill: usize,
+}
+
+impl RateControl {
+    /// The maximum relative change of the step, which is too small to be
+    /// heard as a change of pitch
+    pub const MAX_DEVIATION: f64 = 0.005;
+
+    /// Create a rate control for the `nominal_step` of a resampler and the
+    /// buffer fill level `target_fill` (in any unit)
+    pub fn new(nominal_step: f64, target_fill: usize) -> Self {
+        Self {
+            nominal_step,
+            target_fill: target_fill.max(1),
+        }
+    }
+
+    pub const fn target_fill(&self) -> usize {
+        self.target_fill
+    }
+
+    /// Get the resampler step for the current buffer fill level.
+    ///
+    /// A fuller buffer results in less output samples and vice versa.
+    pub fn step(&self, fill: usize) -> f64 {
+        let target = self.target_fill as f64;
+        let deviation = ((fill as f64 - target) / target).clamp(-1.0, 1.0);
+        self.nominal_step * (1.0 + Self::MAX_DEVIATION * deviation)
+    }
+}
+
+/// Distribute a stereo frame onto the channels of a device frame.
+///
+/// Mono devices get the average of both channels. Devices with more
+/// channels are assumed to use the WAVE channel order: front left and
+/// right come first, the front center (3rd of 3 or at least 5 channels) gets
+/// the average, the low-frequency channel (4th of 6 or 8 channels)
+/// stays silent and the surround channels repeat left and right.
+pub fn mix_channels([l, r]: [f32; 2], frame: &mut [f32]) {
+    let channels = frame.len();
+    if channels == 1 {
+        frame[0] = (l + r) * 0.5;
+        return;
+    }
+    let has_center = channels == 3 || channels >= 5;
+    let has_lfe = channels == 6 || channels == 8;
+    let mut side = 0;
+    for (i, value) in frame.iter_mut().enumerate() {
+        *value = if has_center && i == 2 {
+            (l + r) * 0.5
+        } else if has_lfe && i == 3 {
+            0.0
+        } else {
+            side += 1;
+            if side & 1 == 1 {
+                l
+            } else {
+                r
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(interpolation: Interpolation, input: &[StereoSample]) -> Vec<[f32; 2]> {
+        let mut resampler = Resampler::new(interpolation, 32000, 48000);
+        let mut output = vec![];
+        for &sample in input {
+            resampler.push(sample, |frame| output.push(frame))
+        }
+        output
+    }
+
+    #[test]
+    fn dc_has_unity_gain() {
+        let input = vec![StereoSample::<i16>::new(0x4000, -0x2000); 1000];
+        for interpolation in [
+            Interpolation::Linear,
+            Interpolation::Cubic,
+            Interpolation::Sinc,
+        ] {
+            // skip the samples, that still depend on the initial silence
+            for [l, r] in &resample(interpolation, &input)[TAPS * 2..] {
+                assert!((l - 0.5).abs() < 1e-4, "{:?}: {}", interpolation, l);
+                assert!((r + 0.25).abs() < 1e-4, "{:?}: {}", interpolation, r);
+            }
+        }
+    }
+
+    #[test]
+    fn output_count_matches_rates() {
+        let output = resample(
+            Interpolation::Sinc,
+            &[StereoSample::<i16>::new(0, 0); 32000],
+        );
+        assert!((47999..=48001).contains(&output.len()), "{}", output.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_step_is_rejected() {
+        Resampler::new(Interpolation::Linear, 32000, 48000).set_step(0.0)
+    }
+
+    #[test]
+    fn channel_layouts() {
+        let mix = |channels: usize| {
+            let mut frame = vec![f32::NAN; channels];
+            mix_channels([1.0, 0.5], &mut frame);
+            frame
+        };
+        assert_eq!(mix(1), [0.75]);
+        assert_eq!(mix(2), [1.0, 0.5]);
+        assert_eq!(mix(3), [1.0, 0.5, 0.75]);
+        assert_eq!(mix(6), [1.0, 0.5, 0.75, 0.0, 1.0, 0.5]);
+    }
+}
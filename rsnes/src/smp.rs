@@ -1,10 +1,10 @@
 use crate::{
     backend::AudioBackend as Backend,
-    spc700::Spc700,
+    spc700::{Spc700, StereoSample, VoiceMask},
     timing::{Cycles, APU_CPU_TIMING_PROPORTION_NTSC, APU_CPU_TIMING_PROPORTION_PAL},
 };
 use save_state::{InSaveState, SaveStateDeserializer, SaveStateSerializer};
-use save_state_macro::InSaveState;
+use std::collections::VecDeque;
 use std::sync::mpsc::{channel, Receiver, RecvError, Sender};
 
 #[derive(Debug, Clone)]
@@ -20,7 +20,15 @@ enum ThreadCommand {
         action: Option<Action>,
     },
     SaveState(Box<Spc700>),
-    GetSaveState,
+    /// Request the state `cycles` cycles ahead of the last synchronization
+    GetSaveState {
+        cycles: Cycles,
+    },
+    MixSamples(Vec<StereoSample>),
+    FlushSamples,
+    SetVoiceMask(VoiceMask),
+    SetVoiceRecording(bool),
+    Reset,
     KillMe,
 }
 
@@ -32,22 +40,156 @@ enum MainCommand {
 
 type ReturnType = Result<(), RecvError>;
 
+/// A failure of the S-SMP thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmpError {
+    /// The thread stopped without a known reason
+    Disconnected,
+    /// The thread panicked with the given message
+    Panicked(String),
+    /// The thread answered a request with the wrong message
+    UnexpectedAnswer,
+}
+
+impl std::fmt::Display for SmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Disconnected => write!(f, "the S-SMP thread stopped unexpectedly"),
+            Self::Panicked(msg) => write!(f, "the S-SMP thread panicked ({})", msg),
+            Self::UnexpectedAnswer => write!(f, "unexpected answer from the S-SMP thread"),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Thread {
-    join_handle: Option<std::thread::JoinHandle<ReturnType>>,
+    join_handle: std::thread::JoinHandle<ReturnType>,
     send: Sender<ThreadCommand>,
     recv: Receiver<MainCommand>,
 }
 
-#[derive(Debug, InSaveState)]
+impl Thread {
+    fn send(&self, command: ThreadCommand) -> Result<(), SmpError> {
+        self.send.send(command).map_err(|_| SmpError::Disconnected)
+    }
+
+    fn recv(&self) -> Result<MainCommand, SmpError> {
+        self.recv.recv().map_err(|_| SmpError::Disconnected)
+    }
+
+    fn get_save_state(&self, cycles: Cycles) -> Result<Spc700, SmpError> {
+        self.send(ThreadCommand::GetSaveState { cycles })?;
+        match self.recv()? {
+            MainCommand::SaveState(spc) => Ok(*spc),
+            MainCommand::Data(_) => Err(SmpError::UnexpectedAnswer),
+        }
+    }
+
+    /// Stop the thread and wait for it to finish
+    fn join(self) -> Result<(), SmpError> {
+        let _ = self.send.send(ThreadCommand::KillMe);
+        match self.join_handle.join() {
+            Ok(_) => Ok(()),
+            Err(payload) => Err(SmpError::Panicked(
+                payload
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string()),
+            )),
+        }
+    }
+}
+
+/// Queue of external audio samples (e.g. from the MSU-1),
+/// that get mixed into the S-DSP output
+#[derive(Debug, Default)]
+struct Mixer {
+    queue: VecDeque<StereoSample>,
+}
+
+impl Mixer {
+    const MAX_QUEUE_SIZE: usize = 0x1000;
+
+    fn extend(&mut self, samples: Vec<StereoSample>) {
+        self.queue.extend(samples);
+        if self.queue.len() > Self::MAX_QUEUE_SIZE {
+            let overflow = self.queue.len() - Self::MAX_QUEUE_SIZE;
+            self.queue.drain(..overflow);
+        }
+    }
+
+    fn mix(&mut self, sample: StereoSample) -> StereoSample {
+        match self.queue.pop_front() {
+            Some(external) => sample + external,
+            None => sample,
+        }
+    }
+}
+
+/// Samples, that get passed to the backend at once
+#[derive(Debug, Default)]
+struct SampleBuffer {
+    samples: Vec<StereoSample>,
+    /// The outputs of the single voices, only collected while recording them
+    voices: Option<Vec<[StereoSample; 8]>>,
+}
+
+impl SampleBuffer {
+    /// Flush even without a frame end to keep the latency bounded
+    const MAX_SIZE: usize = 0x400;
+
+    fn push<B: Backend>(&mut self, sample: StereoSample, spc: &Spc700, backend: &mut B) {
+        self.samples.push(sample);
+        if let Some(voices) = &mut self.voices {
+            voices.push(spc.dsp().voice_outputs())
+        }
+        if self.samples.len() >= Self::MAX_SIZE {
+            self.flush(backend)
+        }
+    }
+
+    fn flush<B: Backend>(&mut self, backend: &mut B) {
+        if let Some(voices) = self.voices.as_mut().filter(|voices| !voices.is_empty()) {
+            backend.push_voice_samples(voices);
+            voices.clear();
+        }
+        if !self.samples.is_empty() {
+            backend.push_samples(&self.samples);
+            self.samples.clear();
+        }
+    }
+
+    fn set_voice_recording<B: Backend>(&mut self, enabled: bool, backend: &mut B) {
+        self.flush(backend);
+        self.voices = enabled.then(Vec::new);
+    }
+}
+
+#[derive(Debug)]
 pub struct Smp<B: Backend> {
     pub spc: Option<Spc700>,
-    #[except((|_v, _s| ()), (|_v, _s| ()))]
     pub backend: Option<B>,
-    #[except(Self::serialize_save_state, Self::deserialize_save_state)]
     thread: Option<Thread>,
+    mixer: Mixer,
+    buffer: SampleBuffer,
     timing_proportion: (Cycles, Cycles),
     master_cycles: Cycles,
+    /// Not part of the emulated state, so it survives replacing the state
+    voice_mask: VoiceMask,
+    error: Option<SmpError>,
+}
+
+/// Get a copy of `spc`, that ran `cycles` more cycles.
+///
+/// The samples of these cycles get discarded, they are produced
+/// again, when the original catches up.
+fn run_copy(spc: &Spc700, cycles: Cycles, mask: VoiceMask) -> Spc700 {
+    let mut spc = spc.clone();
+    for _ in 0..cycles {
+        let _ = spc.run_cycle_with_mask(mask);
+    }
+    spc
 }
 
 fn threaded_spc<B: Backend>(
@@ -56,13 +198,16 @@ fn threaded_spc<B: Backend>(
     send: Sender<MainCommand>,
     recv: Receiver<ThreadCommand>,
 ) -> ReturnType {
+    let mut mixer = Mixer::default();
+    let mut buffer = SampleBuffer::default();
+    let mut mask = VoiceMask::default();
     loop {
         match recv.recv()? {
             ThreadCommand::RunCycles { cycles, action } => {
                 // synchronize
                 for _ in 0..cycles {
-                    if let Some(sample) = spc.run_cycle() {
-                        backend.push_sample(sample)
+                    if let Some(sample) = spc.run_cycle_with_mask(mask) {
+                        buffer.push(mixer.mix(sample), &spc, &mut backend)
                     }
                 }
                 // run action
@@ -77,8 +222,20 @@ fn threaded_spc<B: Backend>(
                 }
             }
             ThreadCommand::SaveState(new_spc) => spc = *new_spc,
-            ThreadCommand::GetSaveState => {
-                let _ = send.send(MainCommand::SaveState(Box::new(spc.clone())));
+            ThreadCommand::GetSaveState { cycles } => {
+                let _ = send.send(MainCommand::SaveState(Box::new(run_copy(
+                    &spc, cycles, mask,
+                ))));
+            }
+            ThreadCommand::MixSamples(samples) => mixer.extend(samples),
+            ThreadCommand::FlushSamples => buffer.flush(&mut backend),
+            ThreadCommand::SetVoiceMask(new_mask) => mask = new_mask,
+            ThreadCommand::SetVoiceRecording(enabled) => {
+                buffer.set_voice_recording(enabled, &mut backend)
+            }
+            ThreadCommand::Reset => {
+                spc.reset();
+                mixer = Mixer::default();
             }
             ThreadCommand::KillMe => break Ok(()),
         }
@@ -97,7 +254,7 @@ impl<B: Backend> Smp<B> {
             let ((m_send, m_recv), (t_send, t_recv)) = (channel(), channel());
             let handle = std::thread::spawn(move || threaded_spc(spc, backend, m_send, t_recv));
             let thread = Some(Thread {
-                join_handle: Some(handle),
+                join_handle: handle,
                 send: t_send,
                 recv: m_recv,
             });
@@ -105,16 +262,24 @@ impl<B: Backend> Smp<B> {
                 spc: None,
                 backend: None,
                 thread,
+                mixer: Mixer::default(),
+                buffer: SampleBuffer::default(),
                 timing_proportion,
                 master_cycles: 0,
+                voice_mask: VoiceMask::default(),
+                error: None,
             }
         } else {
             Self {
                 spc: Some(spc),
                 backend: Some(backend),
                 thread: None,
+                mixer: Mixer::default(),
+                buffer: SampleBuffer::default(),
                 timing_proportion,
                 master_cycles: 0,
+                voice_mask: VoiceMask::default(),
+                error: None,
             }
         }
     }
@@ -130,96 +295,293 @@ impl<B: Backend> Smp<B> {
         cycles
     }
 
-    fn refresh_no_thread(spc: &mut Spc700, backend: &mut B, cycles: Cycles) {
+    fn refresh_no_thread(
+        spc: &mut Spc700,
+        backend: &mut B,
+        mixer: &mut Mixer,
+        buffer: &mut SampleBuffer,
+        mask: VoiceMask,
+        cycles: Cycles,
+    ) {
         for _ in 0..cycles {
-            if let Some(sample) = spc.run_cycle() {
-                backend.push_sample(sample)
+            if let Some(sample) = spc.run_cycle_with_mask(mask) {
+                buffer.push(mixer.mix(sample), spc, backend)
             }
         }
     }
 
+    /// Run `f` with the thread. On failure, the thread gets stopped
+    /// and the error is kept for [`Smp::error`].
+    fn with_thread<T, F: FnOnce(&Thread) -> Result<T, SmpError>>(&mut self, f: F) -> Option<T> {
+        match f(self.thread.as_ref()?) {
+            Ok(val) => Some(val),
+            Err(err) => {
+                // the reason of a disconnect is found by joining the thread
+                let err = match self.thread.take().map(Thread::join) {
+                    Some(Err(panic)) => panic,
+                    _ => err,
+                };
+                self.error.get_or_insert(err);
+                None
+            }
+        }
+    }
+
+    /// Get the failure of the S-SMP thread.
+    ///
+    /// After a failure the S-SMP stops, produces no audio and
+    /// reads from its ports return 0.
+    pub fn error(&self) -> Option<&SmpError> {
+        self.error.as_ref()
+    }
+
+    /// Pass the collected samples to the backend, e.g. at the end of a frame
+    pub fn flush_samples(&mut self) {
+        if let Some(backend) = &mut self.backend {
+            self.buffer.flush(backend)
+        } else {
+            self.with_thread(|thread| thread.send(ThreadCommand::FlushSamples));
+        }
+    }
+
+    /// Like [`Smp::flush_samples`], but with a thread, wait until the samples
+    /// got passed to the backend, e.g. before stopping a recording
+    pub fn flush_samples_and_wait(&mut self) {
+        self.flush_samples();
+        // the thread answers after handling all previous commands
+        self.with_thread(|thread| thread.get_save_state(0).map(drop));
+    }
+
+    /// Queue external samples to be mixed into the S-DSP output.
+    ///
+    /// The S-SMP gets synchronized first, so that the samples get mixed
+    /// at the same position with and without a thread.
+    pub fn mix_samples(&mut self, samples: Vec<StereoSample>) {
+        self.refresh();
+        if self.spc.is_some() {
+            self.mixer.extend(samples)
+        } else {
+            self.with_thread(|thread| thread.send(ThreadCommand::MixSamples(samples)));
+        }
+    }
+
     pub fn refresh(&mut self) {
         let cycles = self.refresh_counters();
         if let (Some(spc), Some(backend)) = (&mut self.spc, &mut self.backend) {
-            Self::refresh_no_thread(spc, backend, cycles)
-        } else if let Some(thread) = &mut self.thread {
-            let _ = thread.send.send(ThreadCommand::RunCycles {
+            Self::refresh_no_thread(
+                spc,
+                backend,
+                &mut self.mixer,
+                &mut self.buffer,
+                self.voice_mask,
                 cycles,
-                action: None,
-            });
+            )
         } else {
-            unreachable!()
+            self.with_thread(|thread| {
+                thread.send(ThreadCommand::RunCycles {
+                    cycles,
+                    action: None,
+                })
+            });
         }
     }
 
     pub fn read_output_port(&mut self, addr: u8) -> u8 {
         let cycles = self.refresh_counters();
         if let (Some(spc), Some(backend)) = (&mut self.spc, &mut self.backend) {
-            Self::refresh_no_thread(spc, backend, cycles);
-            spc.output[usize::from(addr & 3)]
-        } else if let Some(thread) = &mut self.thread {
-            let _ = thread.send.send(ThreadCommand::RunCycles {
+            Self::refresh_no_thread(
+                spc,
+                backend,
+                &mut self.mixer,
+                &mut self.buffer,
+                self.voice_mask,
                 cycles,
-                action: Some(Action::ReadOutputPort { addr }),
-            });
-            // TODO: dont unwrap, make it more elegant
-            match thread.recv.recv().unwrap() {
-                MainCommand::Data(d) => d,
-                _ => panic!(),
-            }
+            );
+            spc.output[usize::from(addr & 3)]
         } else {
-            unreachable!()
+            self.with_thread(|thread| {
+                thread.send(ThreadCommand::RunCycles {
+                    cycles,
+                    action: Some(Action::ReadOutputPort { addr }),
+                })?;
+                match thread.recv()? {
+                    MainCommand::Data(d) => Ok(d),
+                    MainCommand::SaveState(_) => Err(SmpError::UnexpectedAnswer),
+                }
+            })
+            .unwrap_or(0)
         }
     }
 
     pub fn write_input_port(&mut self, addr: u8, data: u8) {
         let cycles = self.refresh_counters();
         if let (Some(spc), Some(backend)) = (&mut self.spc, &mut self.backend) {
-            Self::refresh_no_thread(spc, backend, cycles);
-            spc.input[usize::from(addr & 3)] = data
-        } else if let Some(thread) = &mut self.thread {
-            let _ = thread.send.send(ThreadCommand::RunCycles {
+            Self::refresh_no_thread(
+                spc,
+                backend,
+                &mut self.mixer,
+                &mut self.buffer,
+                self.voice_mask,
                 cycles,
-                action: Some(Action::WriteInputPort { addr, data }),
+            );
+            spc.input[usize::from(addr & 3)] = data
+        } else {
+            self.with_thread(|thread| {
+                thread.send(ThreadCommand::RunCycles {
+                    cycles,
+                    action: Some(Action::WriteInputPort { addr, data }),
+                })
             });
+        }
+    }
+
+    /// Reset the SPC700 and the S-DSP. The audio RAM keeps its contents.
+    pub fn reset(&mut self) {
+        self.master_cycles = 0;
+        self.mixer = Mixer::default();
+        if let Some(spc) = &mut self.spc {
+            spc.reset()
+        } else {
+            self.with_thread(|thread| thread.send(ThreadCommand::Reset));
+        }
+    }
+
+    /// Reinitialize the SPC700 and the S-DSP including the audio RAM
+    pub fn power_cycle(&mut self) {
+        self.master_cycles = 0;
+        self.mixer = Mixer::default();
+        self.load_spc(Spc700::default())
+    }
+
+    /// Replace the state of the SPC700 and the S-DSP
+    fn load_spc(&mut self, new_spc: Spc700) {
+        if let Some(spc) = &mut self.spc {
+            *spc = new_spc;
         } else {
-            unreachable!()
+            self.with_thread(|thread| thread.send(ThreadCommand::SaveState(Box::new(new_spc))));
+        }
+    }
+
+    /// Exclude S-DSP voices from the audio output
+    pub fn set_voice_mask(&mut self, mask: VoiceMask) {
+        // the samples until now are produced with the previous mask
+        self.refresh();
+        self.voice_mask = mask;
+        if self.spc.is_none() {
+            self.with_thread(|thread| thread.send(ThreadCommand::SetVoiceMask(mask)));
+        }
+    }
+
+    pub const fn voice_mask(&self) -> VoiceMask {
+        self.voice_mask
+    }
+
+    /// Start or stop passing the outputs of the single S-DSP voices to
+    /// [`Backend::push_voice_samples`]
+    pub fn set_voice_recording(&mut self, enabled: bool) {
+        if let Some(backend) = &mut self.backend {
+            self.buffer.set_voice_recording(enabled, backend)
+        } else {
+            self.with_thread(|thread| thread.send(ThreadCommand::SetVoiceRecording(enabled)));
+        }
+    }
+
+    /// Synchronize the S-SMP to the current master cycle and make sure, that
+    /// the thread is still running, e.g. before creating a save state.
+    ///
+    /// A failure of the thread gets recorded in [`Smp::error`].
+    pub fn synchronize(&mut self) {
+        self.refresh();
+        self.with_thread(|thread| thread.get_save_state(0).map(drop));
+    }
+
+    /// Get a copy of the SPC700 and S-DSP state at the current master cycle.
+    ///
+    /// The state is the same with and without a thread.
+    pub fn get_spc(&self) -> Result<Spc700, SmpError> {
+        let cycles = self.master_cycles / self.timing_proportion.0;
+        if let Some(spc) = &self.spc {
+            Ok(run_copy(spc, cycles, self.voice_mask))
+        } else if let Some(thread) = &self.thread {
+            thread.get_save_state(cycles)
+        } else {
+            Err(self.error.clone().unwrap_or(SmpError::Disconnected))
         }
     }
 
     pub fn is_threaded(&self) -> bool {
         self.thread.is_some()
     }
+}
 
-    fn serialize_save_state(thread: &Option<Thread>, ser: &mut SaveStateSerializer) {
-        // TODO: do not unwrap
-        if let Some(thread) = thread {
-            thread.send.send(ThreadCommand::GetSaveState).unwrap();
-            match thread.recv.recv().unwrap() {
-                MainCommand::SaveState(new_spc) => {
-                    new_spc.serialize(ser);
-                }
-                _ => panic!(),
-            }
+/// The save state contains the SPC700 and S-DSP state synchronized to
+/// the current master cycle (see [`Smp::get_spc`]), so that it does
+/// not depend on the use of a thread or on the time of the last synchronization.
+///
+/// Serializing panics, if the S-SMP thread failed. Call [`Smp::synchronize`]
+/// and check [`Smp::error`] before creating a save state.
+impl<B: Backend> InSaveState for Smp<B> {
+    fn serialize(&self, state: &mut SaveStateSerializer) {
+        match self.get_spc() {
+            Ok(spc) => spc.serialize(state),
+            Err(err) => panic!("cannot save the S-SMP state ({})", err),
         }
+        self.timing_proportion.serialize(state);
+        (self.master_cycles % self.timing_proportion.0).serialize(state);
     }
 
-    fn deserialize_save_state(thread: &mut Option<Thread>, deser: &mut SaveStateDeserializer) {
-        if let Some(thread) = thread {
-            let mut spc = Spc700::default();
-            spc.deserialize(deser);
-            let _ = thread.send.send(ThreadCommand::SaveState(Box::new(spc)));
-        }
+    fn deserialize(&mut self, state: &mut SaveStateDeserializer) {
+        let mut spc = Spc700::default();
+        spc.deserialize(state);
+        self.timing_proportion.deserialize(state);
+        self.master_cycles.deserialize(state);
+        self.load_spc(spc);
     }
 }
 
 impl<B: Backend> Drop for Smp<B> {
     fn drop(&mut self) {
-        if let Some(thread) = &mut self.thread {
-            drop(thread.send.send(ThreadCommand::KillMe));
-            if let Some(Ok(Err(err))) = thread.join_handle.take().map(|t| t.join()) {
-                todo!("throw useful error ({})", err)
+        // a panic of the thread has already been printed by the panic hook
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::AudioDummy;
+
+    fn save_state(smp: &mut Smp<AudioDummy>) -> Vec<u8> {
+        smp.synchronize();
+        assert_eq!(smp.error(), None);
+        let mut state = SaveStateSerializer { data: vec![] };
+        smp.serialize(&mut state);
+        state.data
+    }
+
+    #[test]
+    fn threaded_state_equals_unthreaded_state() {
+        let mut smps = [
+            Smp::new(AudioDummy, false, false),
+            Smp::new(AudioDummy, false, true),
+        ];
+        let mut outputs = [vec![], vec![]];
+        for (smp, outputs) in smps.iter_mut().zip(&mut outputs) {
+            for i in 0..2000u16 {
+                smp.tick(60 + i % 7);
+                match i % 5 {
+                    0 => outputs.push(smp.read_output_port(i as u8)),
+                    1 => smp.write_input_port(i as u8, (i >> 2) as u8),
+                    2 => smp.refresh(),
+                    3 if i == 1003 => smp.set_voice_mask(VoiceMask { mute: 1, solo: 0 }),
+                    _ => (),
+                }
             }
         }
+        assert_eq!(outputs[0], outputs[1]);
+        let [unthreaded, threaded] = &mut smps;
+        assert_eq!(save_state(unthreaded), save_state(threaded));
     }
 }
@@ -4,6 +4,7 @@
 //!
 //! - <https://wiki.superfamicom.org/spc700-reference>
 //! - <https://emudev.de/q00-snes/spc700-the-audio-processor/>
+//! - <https://problemkaputt.de/fullsnes.htm> (undocumented I/O register behaviour)
 //! - The first of the two official SNES documentation books
 
 use crate::timing::Cycles;
@@ -98,7 +99,23 @@ static CYCLES: [Cycles; 256] = [
        2, 8, 4, 5, 4, 5, 5, 6,   3, 4, 5, 4, 2, 2, 4, 2,  // f^
 ];
 
-const F0_RESET: u8 = 0x80;
+/// Value of the TEST register after reset: timers enabled, RAM writable
+const TEST_RESET: u8 = 0x0a;
+
+/// Length of a bus cycle in SPC700 cycles for the wait states 0 to 3 of TEST
+const WAIT_CYCLE_LENGTHS: [Cycles; 4] = [1, 2, 5, 10];
+/// Timer clocks during a bus cycle for the wait states 0 to 3 of TEST
+const WAIT_TIMER_CLOCKS: [Cycles; 4] = [1, 2, 4, 8];
+
+/// The bus cycles of the running instruction, that are slowed down by wait states
+#[derive(Debug, Default, Clone, Copy)]
+struct BusCycles {
+    accesses: Cycles,
+    /// Additional SPC700 cycles
+    wait_cycles: Cycles,
+    /// SPC700 cycles, in which the timers do not advance
+    timer_lag: Cycles,
+}
 
 /// Flags
 pub mod flags {
@@ -114,9 +131,10 @@ pub mod flags {
     pub const SIGN: u8 = 0x80;
 }
 
+/// The envelope phase of a voice
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum AdsrPeriod {
+pub enum AdsrPeriod {
     Attack = 0,
     Decay = 1,
     Sustain = 2,
@@ -290,6 +308,39 @@ impl Voice {
     }
 }
 
+/// Voices, which are excluded from the audio output (e.g. for debugging)
+///
+/// Bit `n` of each mask selects voice `n`. If any voice is soloed,
+/// only the soloed voices are audible.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceMask {
+    pub mute: u8,
+    pub solo: u8,
+}
+
+impl VoiceMask {
+    pub const fn is_audible(&self, voice: u8) -> bool {
+        let audible = if self.solo > 0 { self.solo } else { !self.mute };
+        (audible >> voice) & 1 > 0
+    }
+}
+
+/// A read-only snapshot of the state of a single S-DSP voice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceState {
+    /// The sample rate relative to 32kHz in units of 1/0x1000 (PITCH)
+    pub pitch: u16,
+    /// The number of the sample in the sample directory (SRCN)
+    pub srcn: u8,
+    pub period: AdsrPeriod,
+    /// The envelope level in the range 0-0x7ff
+    pub envelope: u16,
+    /// The address of the current BRR block
+    pub brr_addr: u16,
+    /// The voice got keyed on and was not yet released
+    pub is_keyed_on: bool,
+}
+
 #[derive(Debug, Clone, InSaveState)]
 pub struct DspCounter(u16);
 
@@ -350,6 +401,12 @@ pub struct Dsp {
     echo_sample: StereoSample,
 
     global_output: StereoSample,
+    /// The outputs of every voice, only used to record voices separately
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    voice_outputs: [StereoSample; 8],
+    /// `voice_outputs` of the samples in `global_output`
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    global_voice_outputs: [StereoSample; 8],
 }
 
 impl Dsp {
@@ -395,6 +452,57 @@ impl Dsp {
             echo_sample: StereoSample::<i16>::new2(0),
 
             global_output: StereoSample::<i16>::new2(0),
+            voice_outputs: [StereoSample::<i16>::new2(0); 8],
+            global_voice_outputs: [StereoSample::<i16>::new2(0); 8],
+        }
+    }
+
+    /// Reset the internal state of the S-DSP.
+    ///
+    /// The register values are retained, except that FLG gets set to
+    /// soft reset, mute and echo write disable.
+    pub fn reset(&mut self) {
+        let mut mem = self.mem;
+        mem[regs::FLG as usize] = 0xe0;
+        *self = Self { mem, ..Self::new() }
+    }
+
+    /// Replace the register file with `regs` and reset the internal state.
+    ///
+    /// Voices, whose bits are set in KON, get keyed on again.
+    pub fn load_registers(&mut self, regs: &[u8; 0x80]) {
+        *self = Self {
+            mem: *regs,
+            next_fade_in: regs[usize::from(regs::KON)],
+            ..Self::new()
+        }
+    }
+
+    /// Get the output of every voice, that is part of the last output sample,
+    /// with the voice and the main volume applied, as if the voice was the only one playing.
+    ///
+    /// The echo is not included and the [`VoiceMask`] is ignored.
+    pub fn voice_outputs(&self) -> [StereoSample; 8] {
+        let volume = |reg: u8| i32::from(self.mem[usize::from(reg)] as i8);
+        let (left, right) = (volume(regs::MVOLL), volume(regs::MVOLL | 0x10));
+        let apply = |v: i16, vol: i32| ((i32::from(v) * vol) >> 7).clamp(-0x8000, 0x7fff) as i16;
+        self.global_voice_outputs.map(|output| StereoSample {
+            l: apply(output.l, left),
+            r: apply(output.r, right),
+        })
+    }
+
+    /// Get a snapshot of the state of voice `voice` (0-7)
+    pub fn voice_state(&self, voice: u8) -> VoiceState {
+        let reg = |reg: u8| self.mem[usize::from(((voice & 7) << 4) | reg)];
+        let state = &self.voices[usize::from(voice & 7)];
+        VoiceState {
+            pitch: u16::from_le_bytes([reg(regs::PITCHL), reg(regs::PITCHH) & 0x3f]),
+            srcn: reg(regs::SRCN),
+            period: state.period,
+            envelope: state.gain,
+            brr_addr: state.brr_base,
+            is_keyed_on: state.period != AdsrPeriod::Release,
         }
     }
 
@@ -422,11 +530,18 @@ impl Dsp {
         }
     }
 
+    /// Read register `adr`. The registers are mirrored to 0x80-0xff,
+    /// but writes to these mirrors are ignored.
     pub const fn read(&self, adr: u8) -> u8 {
         self.mem[(adr & 0x7f) as usize]
     }
 
-    pub fn run_step<const STEP: u8>(&mut self, voice: u8, ram: &[u8; MEMORY_SIZE]) {
+    pub fn run_step<const STEP: u8>(
+        &mut self,
+        voice: u8,
+        ram: &[u8; MEMORY_SIZE],
+        mask: VoiceMask,
+    ) {
         macro_rules! vx {
             ($id:ident) => {
                 vx!($id | 0)
@@ -461,9 +576,13 @@ impl Dsp {
                 let sample =
                     ((i32::from(self.output) * i32::from(vx!(VOLL | $channel) as i8)) >> 7).clamp(-0x8000, 0x7fff) as i16;
                 let amp = |s: &mut i16| *s = s.saturating_add(sample);
-                amp(&mut self.main_sample.$i);
-                if (self.echo_enabled >> voice) & 1 > 0 {
-                    amp(&mut self.echo_sample.$i)
+                self.voice_outputs[usize::from(voice)].$i = sample;
+                // muted voices are still visible in OUTX and modulate the pitch
+                if mask.is_audible(voice) {
+                    amp(&mut self.main_sample.$i);
+                    if (self.echo_enabled >> voice) & 1 > 0 {
+                        amp(&mut self.echo_sample.$i)
+                    }
                 }
             }};
         }
@@ -481,9 +600,9 @@ impl Dsp {
                 self.pitch = vx!(PITCHL).into();
             }
             3 => {
-                self.run_step::<10>(voice, ram);
-                self.run_step::<11>(voice, ram);
-                self.run_step::<12>(voice, ram);
+                self.run_step::<10>(voice, ram, mask);
+                self.run_step::<11>(voice, ram, mask);
+                self.run_step::<12>(voice, ram, mask);
             }
             4 => {
                 self.looped_voice_bit = 0;
@@ -723,10 +842,20 @@ impl Dsp {
             .map(|c| (i32::from(c) * fir) >> 6)
     }
 
-    pub fn run_one_step(&mut self, ram: &mut [u8; MEMORY_SIZE]) {
+    /// Run a single step of the 32 steps per sample.
+    ///
+    /// Echo samples are only written to `ram` if `is_ram_writable`
+    /// (bit 1 set and bit 2 cleared in the TEST register).
+    /// The voices excluded by `mask` are not mixed into the output.
+    pub fn run_one_step(
+        &mut self,
+        ram: &mut [u8; MEMORY_SIZE],
+        is_ram_writable: bool,
+        mask: VoiceMask,
+    ) {
         macro_rules! step {
             ($v:literal[$s:literal] $(, $v_:literal[$s_:literal])*) => {{
-                self.run_step::<$s>($v & 7, ram);
+                self.run_step::<$s>($v & 7, ram, mask);
                 $(step!($v_[$s_]));*
             }};
         }
@@ -764,7 +893,7 @@ impl Dsp {
             (right) => { echo_to_ram!(r 2) };
             ($i:ident $off:literal) => {{
                 let sample = take(&mut self.echo_sample.$i);
-                if self.flag_buf & 0x20 == 0 {
+                if self.flag_buf & 0x20 == 0 && is_ram_writable {
                     let adr = self.echo_addr.wrapping_add($off);
                     let [low, high] = sample.to_le_bytes();
                     ram[usize::from(adr)] = low;
@@ -836,11 +965,13 @@ impl Dsp {
                 self.main_sample.r = calculate_echo!(right);
                 let out = take(&mut self.main_sample);
 
-                self.global_output = if reg!(FLG) & 0x40 > 0 {
-                    StereoSample::<i16>::new2(0)
+                if reg!(FLG) & 0x40 > 0 {
+                    self.global_output = StereoSample::<i16>::new2(0);
+                    self.global_voice_outputs = [StereoSample::<i16>::new2(0); 8];
                 } else {
-                    out
-                };
+                    self.global_output = out;
+                    self.global_voice_outputs = self.voice_outputs;
+                }
             }
             28 => {
                 self.dir = reg!(DIR);
@@ -885,6 +1016,17 @@ impl Dsp {
     }
 }
 
+/// The CPU registers of the SPC700
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub sp: u8,
+}
+
 #[derive(Debug, Clone, InSaveState)]
 pub struct Spc700 {
     mem: [u8; MEMORY_SIZE],
@@ -901,26 +1043,34 @@ pub struct Spc700 {
     status: u8,
     pc: u16,
 
+    /// TEST register (0xf0)
+    test: u8,
+    /// bit 7 of CONTROL (0xf1)
+    is_rom_mapped: bool,
+    /// DSPADDR register (0xf2)
+    dsp_addr: u8,
+    /// AUXIO4 and AUXIO5 registers (0xf8-0xf9)
+    aux: [u8; 2],
+
     timer_max: [u8; 3],
     // internal timer ticks ALL in 64kHz
     timers: [u8; 3],
     timer_enable: u8,
     counters: [Cell<u8>; 3],
     dispatch_counter: u16,
+    /// The clock of the timers, that runs slower than `dispatch_counter` with wait states
+    timer_clock: u16,
+    timer_lag: Cycles,
     cycles_ahead: Cycles,
+    #[except((|_v, _s| ()), (|_v, _s| ()))]
+    bus_cycles: Cell<BusCycles>,
     halt: bool,
 }
 
 impl Default for Spc700 {
     fn default() -> Self {
-        const fn generate_power_up_memory() -> [u8; MEMORY_SIZE] {
-            let mut mem = [0; MEMORY_SIZE];
-            mem[0xf0] = F0_RESET;
-            mem
-        }
-        const POWER_UP_MEMORY: [u8; MEMORY_SIZE] = generate_power_up_memory();
         Self {
-            mem: POWER_UP_MEMORY,
+            mem: [0; MEMORY_SIZE],
             input: [0; 4],
             output: [0; 4],
             dsp: Dsp::new(),
@@ -931,12 +1081,20 @@ impl Default for Spc700 {
             pc: 0xffc0,
             status: 2,
 
+            test: TEST_RESET,
+            is_rom_mapped: true,
+            dsp_addr: 0,
+            aux: [0; 2],
+
             timer_max: [0; 3],
             timers: [0; 3],
             timer_enable: 0,
             counters: [Cell::new(0), Cell::new(0), Cell::new(0)],
             dispatch_counter: 0,
+            timer_clock: 0,
+            timer_lag: 0,
             cycles_ahead: 2,
+            bus_cycles: Cell::new(BusCycles::default()),
             halt: false,
         }
     }
@@ -944,7 +1102,8 @@ impl Default for Spc700 {
 
 impl Spc700 {
     pub fn reset(&mut self) {
-        self.mem[0xf0] = F0_RESET;
+        self.test = TEST_RESET;
+        self.is_rom_mapped = true;
         self.input = [0; 4];
         self.output = [0; 4];
         self.a = 0;
@@ -952,15 +1111,129 @@ impl Spc700 {
         self.y = 0;
         self.sp = 0;
         // actually self.read16(0xfffe), but this will
-        // always result in 0xffc0, because mem[0xf0] = 0x80
+        // always result in 0xffc0, because the IPL ROM is mapped
         self.pc = 0xffc0;
         self.status = 0;
         self.halt = false;
-        // TODO: reset dsp
+        self.timers = [0; 3];
+        self.timer_enable = 0;
+        self.counters.iter().for_each(|counter| counter.set(0));
+        self.dispatch_counter = 0;
+        self.timer_clock = 0;
+        self.timer_lag = 0;
+        self.cycles_ahead = 2;
+        self.dsp.reset();
+    }
+
+    /// Create an SPC700 from a snapshot of its RAM, CPU registers and
+    /// S-DSP registers (e.g. from an `.spc` file).
+    ///
+    /// The I/O registers CONTROL, DSPADDR, the input ports, AUXIO, the timer
+    /// dividers and the timer counters are taken from 0xf1-0xff of `mem`.
+    /// TEST gets its value after reset, so that an unusual value in the
+    /// snapshot can neither stop the timers nor protect the RAM.
+    pub fn from_snapshot(mem: &[u8; MEMORY_SIZE], regs: Registers, dsp_regs: &[u8; 0x80]) -> Self {
+        let mut spc = Self {
+            a: regs.a,
+            x: regs.x,
+            y: regs.y,
+            sp: regs.sp,
+            status: regs.status,
+            pc: regs.pc,
+            ..Self::default()
+        };
+        for addr in [0xf2, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc] {
+            spc.write(addr, mem[usize::from(addr)]);
+        }
+        // without clearing the input ports
+        spc.write(0xf1, mem[0xf1] & 0x87);
+        spc.input.copy_from_slice(&mem[0xf4..0xf8]);
+        for (counter, val) in spc.counters.iter().zip(&mem[0xfd..]) {
+            counter.set(val & 0xf)
+        }
+        // the writes to the I/O registers also went to the RAM below
+        spc.mem = *mem;
+        spc.dsp.load_registers(dsp_regs);
+        spc
+    }
+
+    /// Get a snapshot of the RAM, CPU registers and S-DSP registers,
+    /// the counterpart of [`Spc700::from_snapshot`].
+    ///
+    /// The current values of the I/O registers are stored at 0xf0-0xff.
+    /// 0xffc0-0xffff always contains the RAM, even if the IPL ROM is mapped.
+    pub fn snapshot(&self) -> ([u8; MEMORY_SIZE], Registers, [u8; 0x80]) {
+        let mut mem = self.mem;
+        mem[0xf0] = self.test;
+        mem[0xf1] = self.timer_enable | (u8::from(self.is_rom_mapped) << 7);
+        mem[0xf2] = self.dsp_addr;
+        mem[0xf3] = self.dsp.read(self.dsp_addr);
+        mem[0xf4..0xf8].copy_from_slice(&self.input);
+        mem[0xf8..0xfa].copy_from_slice(&self.aux);
+        mem[0xfa..0xfd].copy_from_slice(&self.timer_max);
+        for (val, counter) in mem[0xfd..].iter_mut().zip(&self.counters) {
+            *val = counter.get()
+        }
+        let regs = Registers {
+            pc: self.pc,
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            status: self.status,
+            sp: self.sp,
+        };
+        (mem, regs, self.dsp.mem)
+    }
+
+    pub fn dsp(&self) -> &Dsp {
+        &self.dsp
+    }
+
+    pub fn dsp_mut(&mut self) -> &mut Dsp {
+        &mut self.dsp
     }
 
     pub fn is_rom_mapped(&self) -> bool {
-        self.mem[0xf0] & 0x80 > 0
+        self.is_rom_mapped
+    }
+
+    /// Get the value of the TEST register (0xf0)
+    pub const fn test(&self) -> u8 {
+        self.test
+    }
+
+    /// Count a bus cycle of the running instruction, if TEST enables wait states
+    fn count_bus_cycle(&self, addr: u16) {
+        if self.test & 0xf0 == 0 {
+            return;
+        }
+        // the I/O registers and the IPL ROM use the wait states of
+        // bits 6-7, the RAM uses the wait states of bits 4-5
+        let wait_states = if addr & 0xfff0 == 0xf0 || (addr >= 0xffc0 && self.is_rom_mapped) {
+            self.test >> 6
+        } else {
+            (self.test >> 4) & 3
+        };
+        let wait_states = usize::from(wait_states);
+        let mut bus = self.bus_cycles.get();
+        bus.accesses += 1;
+        bus.wait_cycles += WAIT_CYCLE_LENGTHS[wait_states] - 1;
+        bus.timer_lag += WAIT_CYCLE_LENGTHS[wait_states] - WAIT_TIMER_CLOCKS[wait_states];
+        self.bus_cycles.set(bus);
+    }
+
+    /// Extend the `cycles` of an instruction by the wait states of its bus cycles.
+    ///
+    /// The cycles without a bus access use the wait states of bits 6-7 of TEST.
+    /// The timers slow down with more than one wait state, but the S-DSP
+    /// has its own clock and is not affected at all.
+    fn add_wait_states(&mut self, cycles: Cycles) -> Cycles {
+        let bus = self.bus_cycles.take();
+        let wait_states = usize::from(self.test >> 6);
+        let idle = cycles.saturating_sub(bus.accesses);
+        self.timer_lag += bus.timer_lag
+            + idle * (WAIT_CYCLE_LENGTHS[wait_states] - WAIT_TIMER_CLOCKS[wait_states]);
+        cycles + bus.wait_cycles + idle * (WAIT_CYCLE_LENGTHS[wait_states] - 1)
     }
 
     pub fn read16(&self, addr: u16) -> u16 {
@@ -968,20 +1241,29 @@ impl Spc700 {
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        self.count_bus_cycle(addr);
         match addr {
-            0xf3 => self.dsp.read(self.mem[0xf2]),
+            // TEST, CONTROL and the timer dividers are write-only
+            0xf0..=0xf1 | 0xfa..=0xfc => 0,
+            0xf2 => self.dsp_addr,
+            0xf3 => self.dsp.read(self.dsp_addr),
             0xf4..=0xf7 => self.input[usize::from(addr - 0xf4)],
+            0xf8..=0xf9 => self.aux[usize::from(addr - 0xf8)],
             0xfd..=0xff => self.counters[usize::from(addr - 0xfd)].take(),
-            0xf0..=0xf1 | 0xfa..=0xfc => 0,
-            0xffc0..=0xffff if self.is_rom_mapped() => ROM[(addr & 0x3f) as usize],
+            0xffc0..=0xffff if self.is_rom_mapped => ROM[(addr & 0x3f) as usize],
+            // the RAM is disabled by bit 2 of TEST, which crashes most programs
+            _ if self.test & 0x04 > 0 => 0x5a,
             addr => self.mem[addr as usize],
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        self.count_bus_cycle(addr);
         match addr {
-            0xf0 => todo!("undocumented SPC register TEST(f0) written"),
+            // TEST can only be written with the zero page at 0x00xx
+            0xf0 if self.status & flags::ZERO_PAGE == 0 => self.test = val,
             0xf1 => {
+                self.is_rom_mapped = val & 0x80 > 0;
                 if val & 0x10 > 0 {
                     self.input[0..2].fill(0)
                 }
@@ -997,10 +1279,17 @@ impl Spc700 {
                     }
                 }
             }
-            0xf3 => self.dsp.write(self.mem[0xf2], val),
+            0xf2 => self.dsp_addr = val,
+            0xf3 => self.dsp.write(self.dsp_addr, val),
             0xf4..=0xf7 => self.output[(addr - 0xf4) as usize] = val,
+            0xf8..=0xf9 => self.aux[usize::from(addr - 0xf8)] = val,
             0xfa..=0xfc => self.timer_max[usize::from(addr & 3) ^ 2] = val,
-            addr => self.mem[addr as usize] = val,
+            _ => (),
+        }
+        // every write, even to the I/O registers or below the IPL ROM, reaches
+        // the RAM, if it is writable (bit 1) and not disabled (bit 2 of TEST)
+        if self.test & 0x06 == 0x02 {
+            self.mem[usize::from(addr)] = val
         }
     }
 
@@ -2461,23 +2750,102 @@ impl Spc700 {
     }
 
     pub fn run_cycle(&mut self) -> Option<StereoSample> {
+        self.run_cycle_with_mask(VoiceMask::default())
+    }
+
+    /// Like [`Spc700::run_cycle`], but exclude the voices of `mask` from the output
+    pub fn run_cycle_with_mask(&mut self, mask: VoiceMask) -> Option<StereoSample> {
         if self.cycles_ahead == 0 && !self.halt {
-            self.cycles_ahead = self.dispatch_instruction();
+            if self.test & 0xf0 > 0 {
+                self.bus_cycles.take();
+                let cycles = self.dispatch_instruction();
+                self.cycles_ahead = self.add_wait_states(cycles);
+            } else {
+                self.cycles_ahead = self.dispatch_instruction();
+            }
         }
         self.cycles_ahead = self.cycles_ahead.saturating_sub(1);
-        self.dsp.run_one_step(&mut self.mem);
-        let mut output = None;
-        if self.dispatch_counter & 0xf == 0 {
-            if self.dispatch_counter & 0x1f == 0 {
-                output = Some(self.dsp.global_output);
-                if self.dispatch_counter & 0x7f == 0 {
+        self.dsp
+            .run_one_step(&mut self.mem, self.test & 0x06 == 0x02, mask);
+        let output = (self.dispatch_counter & 0x1f == 0).then_some(self.dsp.global_output);
+        self.dispatch_counter = self.dispatch_counter.wrapping_add(1);
+        if self.timer_lag > 0 {
+            self.timer_lag -= 1;
+        } else {
+            // the timers only run with bit 3 set and bit 0 cleared in TEST
+            if self.timer_clock & 0xf == 0 && self.test & 0x09 == 0x08 {
+                if self.timer_clock & 0x7f == 0 {
                     self.update_timer(0);
                     self.update_timer(1);
                 }
+                self.update_timer(2);
             }
-            self.update_timer(2);
+            self.timer_clock = self.timer_clock.wrapping_add(1);
         }
-        self.dispatch_counter = self.dispatch_counter.wrapping_add(1);
         output
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get an SPC700, that does not execute instructions
+    fn halted_spc() -> Spc700 {
+        Spc700 {
+            halt: true,
+            ..Spc700::default()
+        }
+    }
+
+    #[test]
+    fn test_register_needs_zero_page_0() {
+        let mut spc = halted_spc();
+        spc.status |= flags::ZERO_PAGE;
+        spc.write(0xf0, 0x00);
+        assert_eq!(spc.test(), TEST_RESET);
+        spc.status &= !flags::ZERO_PAGE;
+        spc.write(0xf0, 0x00);
+        assert_eq!(spc.test(), 0x00);
+    }
+
+    #[test]
+    fn test_register_ram_access() {
+        let mut spc = halted_spc();
+        spc.write(0x1234, 0x11);
+        // bit 1 cleared: writes get lost
+        spc.write(0xf0, 0x08);
+        spc.write(0x1234, 0x22);
+        assert_eq!(spc.read(0x1234), 0x11);
+        // bit 2 set: the RAM is disabled
+        spc.write(0xf0, 0x0e);
+        spc.write(0x1234, 0x33);
+        assert_eq!(spc.read(0x1234), 0x5a);
+        spc.write(0xf0, TEST_RESET);
+        assert_eq!(spc.read(0x1234), 0x11);
+    }
+
+    #[test]
+    fn test_register_stops_timers() {
+        for (test, is_running) in [(0x0a, true), (0x0b, false), (0x02, false), (0x03, false)] {
+            let mut spc = halted_spc();
+            spc.write(0xf0, test);
+            spc.write(0xfc, 1);
+            spc.write(0xf1, 0x04);
+            for _ in 0..64 {
+                spc.run_cycle();
+            }
+            let expected = if is_running { 4 } else { 0 };
+            assert_eq!(spc.read(0xff), expected, "TEST = {:#04x}", test);
+        }
+    }
+
+    #[test]
+    fn write_only_registers_read_0() {
+        let mut spc = halted_spc();
+        for addr in [0xf0, 0xf1, 0xfa, 0xfb, 0xfc] {
+            spc.write(addr, 0x0b);
+            assert_eq!(spc.read(addr), 0, "{:#04x}", addr);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Utilities to read and write SPC music files (`*.spc`)
+//!
+//! An SPC file is a snapshot of the sound coprocessor: its 64 KiB RAM,
+//! the CPU and S-DSP registers and optionally ID666 tags with
+//! information about the song.
+//!
+//! # Literature
+//!
+//! - <http://vspcplay.raphnet.net/spc_file_format.txt>
+
+use crate::spc700::{Registers, Spc700, MEMORY_SIZE};
+
+const SIGNATURE: &[u8; 33] = b"SNES-SPC700 Sound File Data v0.30";
+const HAS_TAGS: u8 = 26;
+const MINOR_VERSION: u8 = 30;
+
+const REGISTERS_OFFSET: usize = 0x25;
+const TAGS_OFFSET: usize = 0x2e;
+const RAM_OFFSET: usize = 0x100;
+const DSP_OFFSET: usize = RAM_OFFSET + MEMORY_SIZE;
+const EXTRA_RAM_OFFSET: usize = DSP_OFFSET + 0xc0;
+const MINIMUM_SIZE: usize = EXTRA_RAM_OFFSET + 0x40;
+
+#[derive(Debug)]
+pub enum ReadSpcError {
+    TooSmall(usize),
+    InvalidSignature,
+}
+
+impl std::fmt::Display for ReadSpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooSmall(size) => write!(f, "file too small ({} < {})", size, MINIMUM_SIZE),
+            Self::InvalidSignature => write!(f, "not an SPC file (invalid signature)"),
+        }
+    }
+}
+
+/// The ID666 tags of an SPC file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Id666 {
+    pub song_title: String,
+    pub game_title: String,
+    pub artist: String,
+    pub dumper: String,
+    pub comments: String,
+    /// The date of the dump as `MM/DD/YYYY`
+    pub dump_date: String,
+    /// Seconds to play the song before fading out
+    pub play_seconds: Option<u32>,
+    /// Length of the fade out in milliseconds
+    pub fade_millis: Option<u32>,
+}
+
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn read_decimal(bytes: &[u8]) -> Option<u32> {
+    read_string(bytes).parse().ok().filter(|&v| v > 0)
+}
+
+fn read_binary(bytes: &[u8]) -> Option<u32> {
+    let mut val = [0; 4];
+    val[..bytes.len()].copy_from_slice(bytes);
+    Some(u32::from_le_bytes(val)).filter(|&v| v > 0)
+}
+
+impl Id666 {
+    /// Parse the tags beginning at offset 0x2e of the file.
+    ///
+    /// There is a text and a binary variant of the format, which is
+    /// guessed by the contents of the date and length fields.
+    fn from_bytes(tags: &[u8]) -> Self {
+        let field = |offset: usize, len: usize| &tags[offset - TAGS_OFFSET..][..len];
+        let is_text = field(0x9e, 11)
+            .iter()
+            .all(|&b| b == 0 || b == b'/' || b == b'-' || b.is_ascii_digit())
+            && field(0xa9, 8).iter().all(|&b| b == 0 || b.is_ascii_digit());
+        let common = Self {
+            song_title: read_string(field(0x2e, 32)),
+            game_title: read_string(field(0x4e, 32)),
+            dumper: read_string(field(0x6e, 16)),
+            comments: read_string(field(0x7e, 32)),
+            ..Self::default()
+        };
+        if is_text {
+            Self {
+                artist: read_string(field(0xb1, 32)),
+                dump_date: read_string(field(0x9e, 11)),
+                play_seconds: read_decimal(field(0xa9, 3)),
+                fade_millis: read_decimal(field(0xac, 5)),
+                ..common
+            }
+        } else {
+            let date = field(0x9e, 4);
+            let dump_date = if date.iter().any(|&b| b > 0) {
+                let year = u16::from_le_bytes([date[2], date[3]]);
+                format!("{:02}/{:02}/{:04}", date[1], date[0], year)
+            } else {
+                String::new()
+            };
+            Self {
+                artist: read_string(field(0xb0, 32)),
+                dump_date,
+                play_seconds: read_binary(field(0xa9, 3)),
+                fade_millis: read_binary(field(0xac, 4)),
+                ..common
+            }
+        }
+    }
+}
+
+fn write_string(dst: &mut [u8], text: &str) {
+    let len = text.len().min(dst.len());
+    dst[..len].copy_from_slice(&text.as_bytes()[..len]);
+}
+
+impl Id666 {
+    /// Write the tags in the text format to the file header
+    fn write(&self, header: &mut [u8]) {
+        let mut field = |offset: usize, len: usize, text: &str| {
+            write_string(&mut header[offset..][..len], text)
+        };
+        field(0x2e, 32, &self.song_title);
+        field(0x4e, 32, &self.game_title);
+        field(0x6e, 16, &self.dumper);
+        field(0x7e, 32, &self.comments);
+        field(0x9e, 11, &self.dump_date);
+        let decimal = |val: Option<u32>| val.map(|v| v.to_string()).unwrap_or_default();
+        field(0xa9, 3, &decimal(self.play_seconds.map(|v| v.min(999))));
+        field(0xac, 5, &decimal(self.fade_millis.map(|v| v.min(99999))));
+        field(0xb1, 32, &self.artist);
+    }
+}
+
+/// A loaded SPC file, ready to be played by [`Spc700::run_cycle`]
+#[derive(Debug, Clone)]
+pub struct SpcFile {
+    pub spc: Spc700,
+    pub tags: Option<Id666>,
+}
+
+impl SpcFile {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ReadSpcError> {
+        if bytes.len() < MINIMUM_SIZE {
+            return Err(ReadSpcError::TooSmall(bytes.len()));
+        }
+        if !bytes.starts_with(SIGNATURE) {
+            return Err(ReadSpcError::InvalidSignature);
+        }
+        let [pcl, pch, a, x, y, status, sp] = bytes[REGISTERS_OFFSET..][..7].try_into().unwrap();
+        let regs = Registers {
+            pc: u16::from_le_bytes([pcl, pch]),
+            a,
+            x,
+            y,
+            status,
+            sp,
+        };
+        let mut mem: [u8; MEMORY_SIZE] = bytes[RAM_OFFSET..DSP_OFFSET].try_into().unwrap();
+        // with the IPL ROM mapped, the RAM below it is stored separately
+        if mem[0xf1] & 0x80 > 0 {
+            mem[0xffc0..].copy_from_slice(&bytes[EXTRA_RAM_OFFSET..MINIMUM_SIZE]);
+        }
+        let dsp_regs = bytes[DSP_OFFSET..][..0x80].try_into().unwrap();
+        let tags =
+            (bytes[0x23] == HAS_TAGS).then(|| Id666::from_bytes(&bytes[TAGS_OFFSET..RAM_OFFSET]));
+        Ok(Self {
+            spc: Spc700::from_snapshot(&mem, regs, dsp_regs),
+            tags,
+        })
+    }
+
+    /// Serialize the state of the sound coprocessor and the tags to an SPC file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; MINIMUM_SIZE];
+        bytes[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        bytes[0x21..0x23].fill(0x1a);
+        bytes[0x23] = if self.tags.is_some() { HAS_TAGS } else { 27 };
+        bytes[0x24] = MINOR_VERSION;
+        let (mut mem, regs, dsp_regs) = self.spc.snapshot();
+        let [pcl, pch] = regs.pc.to_le_bytes();
+        bytes[REGISTERS_OFFSET..][..7].copy_from_slice(&[
+            pcl,
+            pch,
+            regs.a,
+            regs.x,
+            regs.y,
+            regs.status,
+            regs.sp,
+        ]);
+        if let Some(tags) = &self.tags {
+            tags.write(&mut bytes)
+        }
+        bytes[EXTRA_RAM_OFFSET..].copy_from_slice(&mem[0xffc0..]);
+        if self.spc.is_rom_mapped() {
+            for (addr, val) in (0xffc0..=0xffff).zip(&mut mem[0xffc0..]) {
+                *val = self.spc.read(addr)
+            }
+        }
+        bytes[RAM_OFFSET..DSP_OFFSET].copy_from_slice(&mem);
+        bytes[DSP_OFFSET..][..0x80].copy_from_slice(&dsp_regs);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_file() -> Vec<u8> {
+        let mut bytes = vec![0; MINIMUM_SIZE];
+        bytes[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        bytes[0x23] = HAS_TAGS;
+        bytes
+    }
+
+    #[test]
+    fn text_tags() {
+        let mut bytes = empty_file();
+        write_string(&mut bytes[0x2e..], "Song");
+        write_string(&mut bytes[0x4e..], "Game");
+        write_string(&mut bytes[0x6e..], "Dumper");
+        write_string(&mut bytes[0x9e..], "03/05/2001");
+        write_string(&mut bytes[0xa9..], "125");
+        write_string(&mut bytes[0xac..], "7500");
+        write_string(&mut bytes[0xb1..], "Artist");
+        let tags = SpcFile::from_bytes(&bytes).unwrap().tags.unwrap();
+        assert_eq!(
+            tags,
+            Id666 {
+                song_title: "Song".into(),
+                game_title: "Game".into(),
+                artist: "Artist".into(),
+                dumper: "Dumper".into(),
+                comments: String::new(),
+                dump_date: "03/05/2001".into(),
+                play_seconds: Some(125),
+                fade_millis: Some(7500),
+            }
+        );
+    }
+
+    #[test]
+    fn binary_tags() {
+        let mut bytes = empty_file();
+        write_string(&mut bytes[0x2e..], "Song");
+        bytes[0x9e..0xa2].copy_from_slice(&[5, 3, 0xd1, 0x07]);
+        bytes[0xa9..0xac].copy_from_slice(&[0xc8, 0, 0]);
+        bytes[0xac..0xb0].copy_from_slice(&10_000u32.to_le_bytes());
+        write_string(&mut bytes[0xb0..], "Artist");
+        let tags = SpcFile::from_bytes(&bytes).unwrap().tags.unwrap();
+        assert_eq!(tags.song_title, "Song");
+        assert_eq!(tags.artist, "Artist");
+        assert_eq!(tags.dump_date, "03/05/2001");
+        assert_eq!(tags.play_seconds, Some(200));
+        assert_eq!(tags.fade_millis, Some(10_000));
+    }
+
+    #[test]
+    fn missing_tags() {
+        let mut bytes = empty_file();
+        bytes[0x23] = 27;
+        assert_eq!(SpcFile::from_bytes(&bytes).unwrap().tags, None);
+    }
+
+    #[test]
+    fn round_trip_with_ipl_rom_mapped() {
+        let mut mem = [0; MEMORY_SIZE];
+        for (i, val) in mem.iter_mut().enumerate() {
+            *val = (i * 7 + (i >> 8)) as u8
+        }
+        // map the IPL ROM and stop the timers
+        mem[0xf1] = 0x80;
+        let regs = Registers {
+            pc: 0x1234,
+            a: 1,
+            x: 2,
+            y: 3,
+            status: 4,
+            sp: 0xef,
+        };
+        let file = SpcFile {
+            spc: Spc700::from_snapshot(&mem, regs, &[0x11; 0x80]),
+            tags: Some(Id666 {
+                song_title: "Song".into(),
+                play_seconds: Some(60),
+                ..Id666::default()
+            }),
+        };
+        assert!(file.spc.is_rom_mapped());
+        let bytes = file.to_bytes();
+        // the RAM below the IPL ROM gets stored separately
+        assert_eq!(bytes[EXTRA_RAM_OFFSET..], mem[0xffc0..]);
+        for addr in 0xffc0..=0xffff {
+            assert_eq!(bytes[RAM_OFFSET + usize::from(addr)], file.spc.read(addr));
+        }
+        let loaded = SpcFile::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.tags, file.tags);
+        assert_eq!(loaded.spc.snapshot(), file.spc.snapshot());
+        assert_eq!(loaded.spc.snapshot().0[0xffc0..], mem[0xffc0..]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(matches!(
+            SpcFile::from_bytes(&empty_file()[..MINIMUM_SIZE - 1]),
+            Err(ReadSpcError::TooSmall(size)) if size == MINIMUM_SIZE - 1
+        ));
+        assert!(matches!(
+            SpcFile::from_bytes(&vec![0; MINIMUM_SIZE]),
+            Err(ReadSpcError::InvalidSignature)
+        ));
+    }
+}
@@ -7,6 +7,7 @@
 use crate::{
     cpu::Status,
     device::{Addr24, Device},
+    ppu::{RenderMode, FIRST_VISIBLE_DOT, SCREEN_WIDTH},
 };
 
 pub type Cycles = u32;
@@ -21,8 +22,129 @@ pub(crate) const APU_CPU_TIMING_PROPORTION_PAL: (Cycles, Cycles) = (665, 32);
 pub(crate) const NECDSP_CPU_TIMING_PROPORTION_NTSC: (Cycles, Cycles) = (118125, 45056);
 pub(crate) const NECDSP_CPU_TIMING_PROPORTION_PAL: (Cycles, Cycles) = (40591, 15625);
 
+/// This is the proportion between the cpu clock speed and the S-DSP sample rate (32kHz)
+pub(crate) const MSU1_CPU_TIMING_PROPORTION_NTSC: (Cycles, Cycles) = (118125, 176);
+pub(crate) const MSU1_CPU_TIMING_PROPORTION_PAL: (Cycles, Cycles) = (665, 1);
+
+/// Number of ticks, in which a counter starting at `pos` and increasing
+/// by `n` every tick stays below `target`
+fn ticks_until(pos: i32, target: i32, n: u16) -> u16 {
+    if pos >= target {
+        0
+    } else {
+        let n = i32::from(n);
+        ((target - pos + n - 1) / n).min(u16::MAX.into()) as u16
+    }
+}
+
 impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B, FB> {
+    /// Run the device until the next timing event happened.
+    ///
+    /// This is equivalent to calling [`Device::run_cycle`] repeatedly,
+    /// but all ticks, which would only advance counters, are skipped
+    /// at once. Events are e.g. the end of a CPU instruction, H/V-IRQs,
+    /// HDMA, DMA completion and the rendering and end of a scanline.
+    ///
+    /// Returns the number of master cycles, that were emulated.
+    pub fn run_until_event<const N: u16>(&mut self) -> Cycles {
+        let ticks = self.ticks_until_next_event::<N>();
+        if ticks > 0 {
+            self.skip_ticks::<N>(ticks);
+        }
+        self.run_cycle::<N>();
+        (Cycles::from(ticks) + 1) * Cycles::from(N)
+    }
+
+    /// Count the upcoming ticks of `N` master cycles, in which
+    /// [`Device::run_cycle`] would only advance linear counters.
+    ///
+    /// This is a conservative guess, it never skips an event.
+    fn ticks_until_next_event<const N: u16>(&self) -> u16 {
+        if self.new_scanline || self.new_frame || self.cartridge.as_ref().unwrap().has_sa1() {
+            return 0;
+        }
+        let x = i32::from(self.ppu.get_pos().x);
+        let line_length = i32::from(self.ppu.get_scanline_cycles());
+        // end of scanline
+        let mut ticks = ticks_until(x, line_length - i32::from(N), N);
+        let mut limit = |t: u16| ticks = ticks.min(t);
+        // CPU pause
+        if x < 536 {
+            limit(ticks_until(x, 536, N))
+        } else if x < 536 + 40 {
+            limit(ticks_until(x, 536 + 40, N))
+        }
+        // scanline rendering
+        if self.ppu.render_mode() == RenderMode::Scanline
+            && self.ppu.get_pos().y + 1 < self.ppu.vend()
+            && !self.scanline_drawn
+        {
+            limit(ticks_until(
+                x,
+                line_length - i32::from(crate::ppu::RAY_AHEAD_CYCLES),
+                N,
+            ));
+        }
+        // HDMA
+        if self.do_hdma && !self.ppu.is_in_vblank() {
+            limit(ticks_until(x, 1024, N));
+        }
+        // H-IRQ
+        if self.cpu.nmitimen & 0x10 > 0 && (x - i32::from(N)) >> 2 < i32::from(self.irq_time_h) {
+            limit(ticks_until(x, i32::from(self.irq_time_h) << 2, N));
+        }
+        // CPU and DMA
+        if self.ppu.is_cpu_active() && self.cpu.active {
+            limit(if self.dma.hdma_ahead_cycles > 0 {
+                ticks_until(0, self.dma.hdma_ahead_cycles, N)
+            } else if self.dma.is_dma_running() {
+                ticks_until(0, self.dma.ahead_cycles, N)
+            } else if self.cpu_ahead_cycles > 0 {
+                ticks_until(0, self.cpu_ahead_cycles, N)
+            } else if self.cpu.wait_mode && !self.shall_nmi && !self.shall_irq {
+                u16::MAX
+            } else {
+                0
+            });
+        }
+        ticks
+    }
+
+    /// Advance all counters by `ticks` ticks of `N` master cycles.
+    ///
+    /// No event may happen within these ticks (see [`Device::ticks_until_next_event`]).
+    fn skip_ticks<const N: u16>(&mut self, ticks: u16) {
+        let cycles = ticks * N;
+        self.smp.tick(cycles);
+        self.cartridge.as_mut().unwrap().tick(cycles.into());
+        self.controllers.auto_joypad_timer -= self.controllers.auto_joypad_timer.min(cycles);
+        if self.ppu.is_cpu_active() && self.cpu.active {
+            if self.dma.hdma_ahead_cycles > 0 {
+                self.dma.hdma_ahead_cycles -= i32::from(cycles);
+            } else if self.dma.is_dma_running() {
+                self.dma.ahead_cycles -= i32::from(cycles);
+            } else if self.cpu_ahead_cycles > 0 {
+                self.cpu_ahead_cycles -= i32::from(cycles);
+            } else {
+                // the CPU is waiting for an interrupt (see `Device::run_cpu`)
+                self.cpu_ahead_cycles -= i32::from(ticks) * i32::from(N - 1);
+            }
+        }
+        self.ppu.mut_pos().x += cycles;
+        self.math_registers.tick(cycles);
+    }
+
+    /// Let the dot renderer catch up with the beam, before any register
+    /// may be changed in this cycle
+    fn draw_dots(&mut self) {
+        if self.ppu.render_mode() == RenderMode::Dot {
+            let dot = (self.ppu.get_pos().x >> 2).saturating_sub(FIRST_VISIBLE_DOT);
+            self.ppu.draw_dots(dot)
+        }
+    }
+
     pub fn run_cycle<const N: u16>(&mut self) {
+        self.draw_dots();
         self.smp.tick(N);
         self.cartridge.as_mut().unwrap().tick(N.into());
         let vend = self.ppu.vend();
@@ -54,6 +176,11 @@ impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B,
             self.dma.hdma_ahead_cycles = self.reset_hdma();
         }
         if self.do_hdma && !self.ppu.is_in_vblank() && self.ppu.get_pos().x >= 1024 {
+            // HDMA sets up the registers of the next scanline,
+            // so the current one needs to be completed first
+            if self.ppu.render_mode() == RenderMode::Dot {
+                self.ppu.draw_dots(SCREEN_WIDTH as u16)
+            }
             self.do_hdma = false;
             self.dma.hdma_ahead_cycles = self.do_hdma();
         }
@@ -61,7 +188,8 @@ impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B,
         if vblanked {
             self.ppu.vblank();
         }
-        if self.ppu.get_pos().x + crate::ppu::RAY_AHEAD_CYCLES >= self.ppu.get_scanline_cycles()
+        if self.ppu.render_mode() == RenderMode::Scanline
+            && self.ppu.get_pos().x + crate::ppu::RAY_AHEAD_CYCLES >= self.ppu.get_scanline_cycles()
             && self.ppu.get_pos().y + 1 < vend
             && !self.scanline_drawn
         {
@@ -90,8 +218,10 @@ impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B,
         self.new_frame = false;
         let line_length = self.ppu.get_scanline_cycles();
         // Test if one scanline completed
-        // TODO: Take notice of the interlace mode
         if self.ppu.get_pos().x >= line_length {
+            if let Some(samples) = self.cartridge.as_mut().unwrap().take_msu1_samples() {
+                self.smp.mix_samples(samples)
+            }
             self.ppu.mut_pos().x -= line_length;
             self.ppu.mut_pos().y += 1;
             self.do_hdma = true;
@@ -99,13 +229,13 @@ impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B,
             self.scanline_drawn = false;
             let scanline_count = self.ppu.get_scanline_count();
             // Test if one frame completed
-            // TODO: Take notice of the interlace mode
             if self.ppu.get_pos().y >= scanline_count {
                 self.ppu.mut_pos().y -= scanline_count;
                 self.new_frame = true;
                 self.nmi_vblank_bit.set(false);
                 self.ppu.end_vblank();
                 self.smp.refresh();
+                self.smp.flush_samples();
                 self.cartridge.as_mut().unwrap().refresh_coprocessors();
             } else if self.smp.is_threaded() {
                 // if the S-SMP is threaded, refresh it every scanline
@@ -182,3 +312,159 @@ impl<B: crate::backend::AudioBackend, FB: crate::backend::FrameBuffer> Device<B,
         }) as u8 as Cycles
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{ArrayFrameBuffer, AudioDummy},
+        cartridge::Cartridge,
+    };
+    use save_state::{InSaveState, SaveStateSerializer};
+
+    /// Append the instructions `LDA #val; STA addr`
+    fn store(code: &mut Vec<u8>, addr: u16, val: u8) {
+        code.extend([0xa9, val, 0x8d]);
+        code.extend(addr.to_le_bytes());
+    }
+
+    /// Append the instructions for a DMA on channel `ch` from `$00:src` to `$21:reg`
+    fn dma(code: &mut Vec<u8>, ch: u16, mode: u8, reg: u8, src: u16, size: u16) {
+        let base = 0x4300 | (ch << 4);
+        let [src_low, src_high] = src.to_le_bytes();
+        let [size_low, size_high] = size.to_le_bytes();
+        for (i, val) in [mode, reg, src_low, src_high, 0, size_low, size_high]
+            .into_iter()
+            .enumerate()
+        {
+            store(code, base + i as u16, val);
+        }
+        store(code, 0x420b, 1 << ch);
+    }
+
+    /// Create a 32 KiB LoROM, which renders random graphics with HDMA,
+    /// waits for NMIs and H/V-IRQs and latches the H/V counters in the IRQ handler
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        // random tiles, tilemap and colors at $00:9000 - $00:afff
+        let mut seed = 1u32;
+        for byte in &mut rom[0x1000..0x3000] {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            *byte = (seed >> 16) as u8;
+        }
+        // HDMA table at $00:b000, which scrolls BG1 horizontally
+        let table = [0x20, 0x08, 0x00, 0x30, 0x10, 0x00, 0x40, 0x00, 0x01, 0x00];
+        rom[0x3000..0x3000 + table.len()].copy_from_slice(&table);
+
+        let mut code = vec![0x78]; // SEI
+        store(&mut code, 0x2100, 0x80); // INIDISP: force blank
+        store(&mut code, 0x2105, 0x01); // BGMODE: mode 1
+        store(&mut code, 0x2107, 0x04); // BG1SC: tilemap at $0400
+        store(&mut code, 0x212c, 0x01); // TM: BG1
+        store(&mut code, 0x2115, 0x80); // VMAIN
+        store(&mut code, 0x2116, 0x00);
+        store(&mut code, 0x2117, 0x00);
+        dma(&mut code, 0, 0x01, 0x18, 0x9000, 0x1000);
+        store(&mut code, 0x2121, 0x00);
+        dma(&mut code, 0, 0x00, 0x22, 0xa000, 0x0200);
+        for (i, val) in [0x02, 0x0d, 0x00, 0xb0, 0x00].into_iter().enumerate() {
+            store(&mut code, 0x4310 + i as u16, val);
+        }
+        store(&mut code, 0x420c, 0x02); // HDMAEN
+        store(&mut code, 0x4207, 0x80); // HTIME
+        store(&mut code, 0x4208, 0x00);
+        store(&mut code, 0x4209, 0x40); // VTIME
+        store(&mut code, 0x420a, 0x00);
+        store(&mut code, 0x4200, 0xb1); // NMITIMEN: NMI, H/V-IRQ, auto joypad
+        store(&mut code, 0x2100, 0x0f);
+        code.push(0x58); // CLI
+        let main_loop = code.len();
+        // WAI; INC $10; LDX #$20; DEX; BNE -3; LDA $10; STA $4207
+        code.extend([0xcb, 0xe6, 0x10, 0xa2, 0x20, 0xca, 0xd0, 0xfd]);
+        code.extend([0xa5, 0x10, 0x8d, 0x07, 0x42]);
+        code.extend([
+            0x80,
+            (main_loop as i8).wrapping_sub(code.len() as i8 + 2) as u8,
+        ]);
+
+        let nmi = code.len();
+        // PHA; LDA $4210; INC $11; PLA; RTI
+        code.extend([0x48, 0xad, 0x10, 0x42, 0xe6, 0x11, 0x68, 0x40]);
+
+        let irq = code.len();
+        // PHA; PHX; LDA $4211; LDA $2137; LDX $12
+        code.extend([0x48, 0xda, 0xad, 0x11, 0x42, 0xad, 0x37, 0x21, 0xa6, 0x12]);
+        for counter in [0x3c, 0x3c, 0x3d, 0x3d] {
+            // LDA $21xx; STA $0300,X; INX
+            code.extend([0xad, counter, 0x21, 0x9d, 0x00, 0x03, 0xe8]);
+        }
+        code.extend([0x86, 0x12]); // STX $12
+        store(&mut code, 0x2181, 0x00);
+        store(&mut code, 0x2182, 0x04);
+        store(&mut code, 0x2183, 0x00);
+        dma(&mut code, 2, 0x00, 0x80, 0x9000, 0x0100);
+        code.extend([0xfa, 0x68, 0x40]); // PLX; PLA; RTI
+        rom[..code.len()].copy_from_slice(&code);
+
+        for (vector, offset) in [(0x7ffa, nmi), (0x7ffc, 0), (0x7ffe, irq)] {
+            rom[vector..vector + 2].copy_from_slice(&(0x8000 + offset as u16).to_le_bytes());
+        }
+        rom[0x7fc0..0x7fd5].copy_from_slice(b"RUN UNTIL EVENT TEST ");
+        rom[0x7fd5..0x7fdc].copy_from_slice(&[0x20, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00]);
+        rom[0x7fdc..0x7fe0].copy_from_slice(&[0xff, 0xff, 0x00, 0x00]);
+        let checksum = rom
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte.into()));
+        rom[0x7fdc..0x7fde].copy_from_slice(&(!checksum).to_le_bytes());
+        rom[0x7fde..0x7fe0].copy_from_slice(&checksum.to_le_bytes());
+        rom
+    }
+
+    fn save_state(device: &mut Device<AudioDummy, ArrayFrameBuffer>) -> Vec<u8> {
+        let mut state = SaveStateSerializer { data: vec![] };
+        device.serialize(&mut state);
+        state.data
+    }
+
+    fn assert_run_until_event_equals_run_cycle<const N: u16>() {
+        let rom = test_rom();
+        let mut devices = [(); 2].map(|_| {
+            let mut device = Device::new(AudioDummy, ArrayFrameBuffer::new(), false, false);
+            device.load_cartridge(Cartridge::from_bytes(&rom).unwrap());
+            device
+        });
+        let [stepped, skipping] = &mut devices;
+        let mut frames = 0;
+        while frames < 6 {
+            let cycles = skipping.run_until_event::<N>();
+            for _ in 0..cycles / Cycles::from(N) {
+                stepped.run_cycle::<N>();
+            }
+            assert_eq!(stepped.new_frame, skipping.new_frame);
+            if skipping.new_frame {
+                frames += 1;
+                assert!(
+                    save_state(stepped) == save_state(skipping),
+                    "frame {frames}"
+                );
+                assert!(stepped.ppu.frame_buffer.0 == skipping.ppu.frame_buffer.0);
+            }
+        }
+        // the IRQ handler did run and latched the counters
+        assert_ne!(stepped.read::<u8>(Addr24::new(0x7e, 0x12)), 0);
+    }
+
+    #[test]
+    fn run_until_event_equals_run_cycle() {
+        // two devices do not fit on the stack of a test thread
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(|| {
+                assert_run_until_event_equals_run_cycle::<1>();
+                assert_run_until_event_equals_run_cycle::<2>();
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! Lossless recording of the S-DSP output to WAV files
+//!
+//! # Literature
+//!
+//! - <http://soundfile.sapp.org/doc/WaveFormat/>
+
+use crate::{backend::AudioBackend, spc700::StereoSample};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The S-DSP outputs one sample per 32 SPC700 cycles
+pub const SAMPLE_RATE: u32 = 32000;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BYTES_PER_FRAME: u16 = CHANNELS * 2;
+
+/// Writes 16-bit stereo PCM samples at 32kHz to a WAV file
+#[derive(Debug)]
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_size: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Write the header, whose sizes get corrected by [`WavWriter::finish`]
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        writer.write_all(&(SAMPLE_RATE * u32::from(BYTES_PER_FRAME)).to_le_bytes())?;
+        writer.write_all(&BYTES_PER_FRAME.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[StereoSample]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * usize::from(BYTES_PER_FRAME));
+        for sample in samples {
+            data.extend_from_slice(&sample.l.to_le_bytes());
+            data.extend_from_slice(&sample.r.to_le_bytes());
+        }
+        // a WAV file can contain at most 4 GiB of data
+        let size = u32::try_from(data.len())
+            .ok()
+            .filter(|&size| {
+                self.data_size
+                    .checked_add(size)
+                    .and_then(|total| total.checked_add(HEADER_SIZE))
+                    .is_some()
+            })
+            .ok_or_else(|| io::Error::other("WAV file too large"))?;
+        self.writer.write_all(&data)?;
+        self.data_size += size;
+        Ok(())
+    }
+
+    /// The number of samples written so far
+    pub const fn len(&self) -> u32 {
+        self.data_size / BYTES_PER_FRAME as u32
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.data_size == 0
+    }
+
+    /// Write the final sizes to the header and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer
+            .seek(SeekFrom::Start(u64::from(HEADER_SIZE) - 4))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Get the path of the file, the voice `voice` (0-7) gets recorded to,
+/// when recording to `path` (`song.wav` becomes `song-voice0.wav`)
+pub fn voice_path(path: &Path, voice: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-voice{}.wav", stem, voice))
+}
+
+#[derive(Debug)]
+struct Recording {
+    main: WavWriter<BufWriter<File>>,
+    voices: Option<Vec<WavWriter<BufWriter<File>>>>,
+    /// Delays the main recording until the voice recordings start,
+    /// so that all files are aligned
+    is_waiting_for_voices: bool,
+    /// The first error while writing, which gets reported when stopping
+    error: Option<io::Error>,
+}
+
+impl Recording {
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            self.error.get_or_insert(err);
+        }
+    }
+}
+
+/// Controls the recording of a [`Recorder`] from another thread
+#[derive(Debug, Clone, Default)]
+pub struct RecordingHandle(Arc<Mutex<Option<Recording>>>);
+
+impl RecordingHandle {
+    /// Start recording to the WAV file at `path`. With `voices` every
+    /// S-DSP voice gets recorded to a separate file (see [`voice_path`]).
+    /// This requires [`crate::smp::Smp::set_voice_recording`] and all files
+    /// only start with the first samples of the voices.
+    ///
+    /// A running recording gets stopped before.
+    pub fn start(&self, path: &Path, voices: bool) -> io::Result<()> {
+        self.stop()?;
+        let recording = Recording {
+            main: WavWriter::create(path)?,
+            voices: if voices {
+                Some(
+                    (0..8)
+                        .map(|voice| WavWriter::create(voice_path(path, voice)))
+                        .collect::<io::Result<_>>()?,
+                )
+            } else {
+                None
+            },
+            is_waiting_for_voices: voices,
+            error: None,
+        };
+        *self.0.lock().unwrap() = Some(recording);
+        Ok(())
+    }
+
+    /// Stop recording and finish the files. Returns the first error,
+    /// that occurred while recording or finishing.
+    ///
+    /// All files get finished regardless of errors, so that the
+    /// samples written so far stay readable.
+    pub fn stop(&self) -> io::Result<()> {
+        let Recording {
+            main,
+            voices,
+            mut error,
+            ..
+        } = match self.0.lock().unwrap().take() {
+            Some(recording) => recording,
+            None => return Ok(()),
+        };
+        for writer in core::iter::once(main).chain(voices.into_iter().flatten()) {
+            if let Err(err) = writer.finish() {
+                error.get_or_insert(err);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// The number of samples recorded so far
+    pub fn len(&self) -> u32 {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |rec| rec.main.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An [`AudioBackend`], that passes all samples to another backend
+/// and writes them to a WAV file while recording
+#[derive(Debug)]
+pub struct Recorder<B: AudioBackend> {
+    inner: B,
+    handle: RecordingHandle,
+}
+
+impl<B: AudioBackend> Recorder<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            handle: RecordingHandle::default(),
+        }
+    }
+
+    /// Get a handle to start and stop recording, which stays valid
+    /// after passing the recorder to the emulation
+    pub fn handle(&self) -> RecordingHandle {
+        self.handle.clone()
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: AudioBackend> AudioBackend for Recorder<B> {
+    fn push_sample(&mut self, sample: StereoSample) {
+        self.push_samples(core::slice::from_ref(&sample))
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample]) {
+        self.inner.push_samples(samples);
+        if let Some(recording) = &mut *self.handle.0.lock().unwrap() {
+            if !recording.is_waiting_for_voices {
+                let result = recording.main.write_samples(samples);
+                recording.check(result)
+            }
+        }
+    }
+
+    fn push_voice_samples(&mut self, samples: &[[StereoSample; 8]]) {
+        self.inner.push_voice_samples(samples);
+        if let Some(recording) = &mut *self.handle.0.lock().unwrap() {
+            if let Some(voices) = &mut recording.voices {
+                recording.is_waiting_for_voices = false;
+                let mut result = Ok(());
+                for (voice, writer) in voices.iter_mut().enumerate() {
+                    let samples: Vec<_> = samples.iter().map(|s| s[voice]).collect();
+                    result = result.and(writer.write_samples(&samples));
+                }
+                recording.check(result)
+            }
+        }
+    }
+}